@@ -4,7 +4,8 @@ use bson::{Bson, Document, Regex as BsonRegex};
 use indexmap::{indexmap, IndexMap};
 use teo_parser::r#type::Type;
 use teo_runtime::model::object::input::Input;
-use teo_runtime::model::{Relation, Model};
+use teo_runtime::model::{Index, Relation, Model};
+use teo_runtime::model::index::Type as IndexType;
 use teo_runtime::{teon, Value};
 use teo_result::{Error, Result};
 use teo_runtime::model::field::column_named::ColumnNamed;
@@ -13,21 +14,33 @@ use teo_runtime::traits::named::Named;
 use teo_runtime::model::field::typed::Typed;
 use teo_runtime::namespace::Namespace;
 use crate::bson_ext::teon_value_to_bson;
+use crate::bson_ext::coder::BsonCoder;
+use crate::connector::ConnectionOptions;
+
+/// Prefix of the columns holding computed values, so they never shadow a stored column.
+const COMPUTED_PREFIX: &str = "__computed_";
 
 pub(crate) struct Aggregation { }
 
 impl Aggregation {
 
     fn insert_group_set_unset_for_aggregate(model: &Model, group: &mut Document, set: &mut Document, unset: &mut Vec<String>, k: &str, g: &str, having_mode: bool) {
-        let prefix = if having_mode { "_having" } else { "" };
         let dbk = if k == "_all" { "_all" } else {model.field(k).unwrap().column_name() };
+        Self::insert_group_set_unset_for_column(group, set, unset, k, dbk, g, having_mode)
+    }
+
+    /// The accumulator `g` of the field `k`, stored in column `dbk`, e.g.
+    /// `_push_product_id: { $push: "$product_id" }`, output as `_push.k`.
+    fn insert_group_set_unset_for_column(group: &mut Document, set: &mut Document, unset: &mut Vec<String>, k: &str, dbk: &str, g: &str, having_mode: bool) {
+        let prefix = if having_mode { "_having" } else { "" };
         if g == "count" {
             if k == "_all" {
                 group.insert(format!("{prefix}_count__all"), doc!{"$count": {}});
             } else {
+                // count non-null values, including falsy ones like `0` and `false`
                 group.insert(format!("{prefix}_count_{dbk}"), doc!{
                 "$sum": {
-                    "$cond": [{"$ifNull": [format!("${dbk}"), false]}, 1, 0]
+                    "$cond": [{"$in": [{"$type": format!("${dbk}")}, ["missing", "null"]]}, 0, 1]
                 }
             });
             }
@@ -61,12 +74,12 @@ impl Aggregation {
         }
     }
 
-    pub(crate) fn build_for_aggregate(namespace: &Namespace, model: &Model, value: &Value) -> Result<Vec<Document>> {
-        let mut retval = Self::build(namespace, model, value)?;
+    pub(crate) fn build_for_aggregate(options: &ConnectionOptions, namespace: &Namespace, model: &Model, value: &Value) -> Result<Vec<Document>> {
+        let mut retval = Self::build(options, namespace, model, value)?;
         let by = value.get("by");
         let having = value.get("having");
         let mut aggregates = teon!({});
-        for k in ["_sum", "_count", "_avg", "_min", "_max"] {
+        for k in ["_sum", "_count", "_avg", "_min", "_max", "_push", "_addToSet"] {
             if value.as_dictionary().unwrap().contains_key(k) {
                 aggregates[k] = value.as_dictionary().unwrap().get(k).unwrap().clone();
             }
@@ -75,9 +88,16 @@ impl Aggregation {
             let mut id_for_group_by = doc!{};
             for key in by.as_array().unwrap() {
                 let k = key.as_str().unwrap();
-                let dbk = model.field(k).unwrap().column_name();
+                let field = model.field(k).unwrap();
+                let dbk = field.column_name();
+                // decimals written as doubles and as decimals wouldn't share a group
+                let group_value: Bson = if matches!(field.r#type().unwrap_optional(), Type::Decimal) {
+                    doc!{"$toDecimal": format!("${dbk}")}.into()
+                } else {
+                    format!("${dbk}").into()
+                };
                 id_for_group_by.insert(dbk, doc!{
-                "$cond": [{"$ifNull": [format!("${dbk}"), false]}, format!("${dbk}"), null]
+                "$cond": [{"$ifNull": [format!("${dbk}"), false]}, group_value, null]
             });
             }
             doc!{"_id": id_for_group_by}
@@ -108,6 +128,13 @@ impl Aggregation {
                 Self::insert_group_set_unset_for_aggregate(model, &mut group, &mut set, &mut unset, k, g, false);
             }
         }
+        if let Some(accumulators) = value.get("_accumulator") {
+            for (name, accumulator) in accumulators.as_dictionary().unwrap() {
+                group.insert(format!("_accumulator_{name}"), doc!{"$accumulator": Self::build_accumulator(options, accumulator)?});
+                set.insert(format!("_accumulator.{name}"), format!("$_accumulator_{name}"));
+                unset.push(format!("_accumulator_{name}"));
+            }
+        }
         retval.push(doc!{"$group": group});
         retval.push(doc!{"$set": set});
         if !unset.is_empty() {
@@ -121,7 +148,7 @@ impl Aggregation {
                 let dbk = model.field(k).unwrap().column_name();
                 for (g, matcher) in o.as_dictionary().unwrap() {
                     let g = g.strip_prefix("_").unwrap();
-                    let matcher_bson = Self::build_where_item(model, &Type::Float, true, matcher)?;
+                    let matcher_bson = Self::build_where_item(options, &Type::Float, true, matcher)?;
                     having_match.insert(format!("_having_{g}.{dbk}"), matcher_bson);
                     let having_group = format!("_having_{g}");
                     if !having_unset.contains(&having_group) {
@@ -146,23 +173,79 @@ impl Aggregation {
         Ok(retval)
     }
 
-    pub(crate) fn build_for_count(namespace: &Namespace, model: &Model, value: &Value) -> Result<Vec<Document>> {
-        let mut retval = Self::build(namespace, model, value)?;
+    /// `{ init, accumulate, accumulateArgs, merge, finalize }` JavaScript
+    /// functions accumulating each group on the server, output under
+    /// `_accumulator` by name. Requires `allow_server_js`.
+    fn build_accumulator(options: &ConnectionOptions, accumulator: &Value) -> Result<Document> {
+        if !options.allow_server_js {
+            return Err(Error::new("`_accumulator` requires `allow_server_js` on the connection"));
+        }
+        let mut retval = doc!{};
+        for key in ["init", "accumulate", "merge", "finalize"] {
+            match accumulator.get(key).map(|function| function.as_str()) {
+                Some(Some(function)) => {
+                    retval.insert(key, function);
+                }
+                Some(None) => return Err(Error::new(format!("accumulator {} must be a JavaScript string", key))),
+                None => if key != "finalize" {
+                    return Err(Error::new(format!("accumulator requires {}", key)));
+                }
+            }
+        }
+        for key in ["accumulateArgs", "initArgs"] {
+            if let Some(args) = accumulator.get(key) {
+                retval.insert(key, teon_value_to_bson(args)?);
+            }
+        }
+        if !retval.contains_key("accumulateArgs") {
+            retval.insert("accumulateArgs", Bson::Array(vec![]));
+        }
+        retval.insert("lang", "js");
+        Ok(retval)
+    }
+
+    pub(crate) fn build_for_count(options: &ConnectionOptions, namespace: &Namespace, model: &Model, value: &Value) -> Result<Vec<Document>> {
+        let mut retval = Self::build(options, namespace, model, value)?;
         retval.push(doc! {"$count": "count"});
         Ok(retval)
     }
 
-    pub(crate) fn build(namespace: &Namespace, model: &Model, value: &Value) -> Result<Vec<Document>> {
+    pub(crate) fn build(options: &ConnectionOptions, namespace: &Namespace, model: &Model, value: &Value) -> Result<Vec<Document>> {
+        Ok(Self::build_with_aliases(options, namespace, model, value)?.0)
+    }
+
+    /// The aliases of a document output by a nested lookup pipeline, whose
+    /// aliases aren't reported. Every alias is the target with the computed
+    /// prefix.
+    pub(crate) fn nested_aliases(document: &Document) -> IndexMap<String, String> {
+        document.keys().filter_map(|key| Some((key.clone(), key.strip_prefix(COMPUTED_PREFIX)?.to_owned()))).collect()
+    }
+
+    /// Build the pipeline along with the output columns it renamed, keyed by
+    /// column and mapped to the field or property they should decode onto.
+    pub(crate) fn build_with_aliases(options: &ConnectionOptions, namespace: &Namespace, model: &Model, value: &Value) -> Result<(Vec<Document>, IndexMap<String, String>)> {
         let mut retval: Vec<Document> = vec![];
-        let r#where = value.get("where");
+        let mut aliases: IndexMap<String, String> = IndexMap::new();
+        // a text search must be the first stage, ahead of relation lookups
+        let hoisted = match value.get("where") {
+            Some(r#where) => Self::hoist_text_search(r#where)?,
+            None => None,
+        };
+        let r#where = match &hoisted {
+            Some((_, r#where)) => Some(r#where),
+            None => value.get("where"),
+        };
+        if let Some((text_search, _)) = &hoisted {
+            retval.push(text_search.clone());
+        }
         let order_by = value.get("orderBy");
         let distinct = value.get("distinct");
-        let skip = value.get("skip");
         let take = value.get("take");
-        let page_size = value.get("pageSize");
-        let page_number = value.get("pageNumber");
         let select = value.get("select");
         let include = value.get("include");
+        let computed = value.get("computed");
+        let slice = value.get("slice");
+        let omit = value.get("omit");
         // if cursor exists, we modify the actual where
         let cursor_where_additions = if let Some(cursor) = value.get("cursor") {
             let cursor = cursor.as_dictionary().unwrap();
@@ -176,19 +259,19 @@ impl Aggregation {
                 }
             }
             let cursor_where_key = if order_asc { "gte" } else { "lte" };
-            let cursor_additional_where = Self::build_where(namespace, model, &teon!({cursor_key: {cursor_where_key: cursor_value}}));
+            let cursor_additional_where = Self::build_where(options, namespace, model, &teon!({cursor_key: {cursor_where_key: cursor_value}}));
             Some(cursor_additional_where?)
         } else {
             None
         };
         // build `$lookup`s for relation where
         if let Some(r#where) = r#where {
-            let lookups_for_relation_where = Self::build_lookups_for_relation_where(namespace, model, r#where)?;
+            let lookups_for_relation_where = Self::build_lookups_for_relation_where(options, namespace, model, r#where)?;
             retval.extend(lookups_for_relation_where)
         }
         // $match
         if let Some(r#where) = r#where {
-            let r#match = Self::build_where(namespace, model, r#where)?;
+            let r#match = Self::build_where(options, namespace, model, r#where)?;
             if !r#match.is_empty() {
                 if let Some(cursor_where_additions) = cursor_where_additions {
                     retval.push(doc!{"$match": {"$and": [r#match, cursor_where_additions]}});
@@ -223,25 +306,44 @@ impl Aggregation {
                 if !sort.is_empty() {
                     retval.push(doc!{"$sort": sort});
                 }
-            } else if let Some(take) = take {
-                if take.to_int64().unwrap() < 0 {
-                    let sort = Self::build_order_by(model, &Self::default_desc_order(model), false)?;
-                    retval.push(doc!{"$sort": sort});
-                }
+            } else if take.is_some() && take.unwrap().to_int64().unwrap() < 0 {
+                let sort = Self::build_order_by(model, &Self::default_desc_order(model), false)?;
+                retval.push(doc!{"$sort": sort});
+            } else if let Some(default_sort) = &options.default_sort {
+                retval.push(doc!{"$sort": default_sort});
             }
         }
         // $skip and $limit
-        if page_size.is_some() && page_number.is_some() {
-            retval.push(doc!{"$skip": ((page_number.unwrap().to_int64().unwrap() - 1) * page_size.unwrap().to_int64().unwrap()) as i64});
-            retval.push(doc!{"$limit": page_size.unwrap().to_int64().unwrap()});
-        } else {
-            if skip.is_some() {
-                retval.push(doc!{"$skip": skip.unwrap().to_int64().unwrap()});
+        retval.extend(Self::build_pagination(options, model.name(), value)?);
+        // windows of array fields
+        if let Some(slice) = slice {
+            let set = Self::build_slice(model, slice)?;
+            if !set.is_empty() {
+                retval.push(doc!{"$set": set});
             }
-            if take.is_some() {
-                retval.push(doc!{"$limit": take.unwrap().to_int64().unwrap().abs()});
+        }
+        // computed columns
+        if let Some(computed) = computed {
+            let add_fields = Self::build_computed(options, model, computed, &mut aliases)?;
+            if !add_fields.is_empty() {
+                retval.push(doc!{"$addFields": add_fields});
             }
         }
+        // counts of related records
+        if let Some(relation_count) = value.get("relationCount") {
+            retval.extend(Self::build_relation_counts(options, namespace, model, relation_count, &mut aliases)?);
+        }
+        // diagnostic metadata
+        if let Some(meta) = value.get("meta") {
+            let add_fields = Self::build_meta(model, meta, &mut aliases)?;
+            if !add_fields.is_empty() {
+                retval.push(doc!{"$addFields": add_fields});
+            }
+        }
+        // creation time embedded in object ids
+        if let Some(id_timestamp) = value.get("idTimestamp") {
+            retval.push(doc!{"$addFields": Self::build_id_timestamp(model, id_timestamp, &mut aliases)?});
+        }
         // distinct or select
         // distinct ($group and $project)
         if let Some(distinct) = distinct {
@@ -252,7 +354,7 @@ impl Aggregation {
                 group_id.insert(val, format!("${val}"));
             }
             let _empty = teon!({});
-            let mut group_data = Self::build_select(model, select.unwrap_or(&teon!({})), Some(distinct))?;
+            let mut group_data = Self::build_select(options, model, select.unwrap_or(&teon!({})), Some(distinct))?;
             group_data.insert("_id", group_id);
             retval.push(doc!{"$group": &group_data});
             if group_data.get("__id").is_some() {
@@ -274,26 +376,341 @@ impl Aggregation {
             }
         } else {
             // $project
-            if let Some(select) = select {
-                if !select.as_dictionary().unwrap().is_empty() {
-                    let select_input = Self::build_select(model, select, distinct)?;
-                    if !select_input.is_empty() {
-                        retval.push(doc!{"$project": select_input})
-                    }
+            if let Some(omit) = omit {
+                if select.is_some() {
+                    return Err(Error::new("select and omit cannot be used together"));
+                }
+                let mut omit_input = Self::build_omit(model, omit)?;
+                omit_input.extend(Self::build_lazy_exclusion(options, model));
+                if !omit_input.is_empty() {
+                    retval.push(doc!{"$project": omit_input})
+                }
+            } else if let Some(select) = select.filter(|select| !select.as_dictionary().unwrap().is_empty()) {
+                let mut select_input = Self::build_select(options, model, select, distinct)?;
+                for alias in aliases.keys() {
+                    select_input.insert(alias, 1);
+                }
+                if !select_input.is_empty() {
+                    retval.push(doc!{"$project": select_input})
+                }
+            } else {
+                let lazy_exclusion = Self::build_lazy_exclusion(options, model);
+                if !lazy_exclusion.is_empty() {
+                    retval.push(doc!{"$project": lazy_exclusion})
                 }
             }
         }
-        // $lookup
+        // $lookup, last so included relations are only looked up for the
+        // records of the page, after $match, $sort, $skip and $limit
         if let Some(include) = include {
-            let mut lookups = Self::build_lookups(namespace, model, include)?;
+            let mut lookups = Self::build_lookups(options, namespace, model, include)?;
             if !lookups.is_empty() {
                 retval.append(&mut lookups);
             }
+            for (relation_name, relation_include) in include.as_dictionary().unwrap() {
+                if let Some(into) = relation_include.get("keyedInto") {
+                    retval.extend(Self::build_keyed_relation(namespace, model, relation_name, relation_include, into, &mut aliases)?);
+                }
+            }
+        }
+        Ok((retval, aliases))
+    }
+
+    /// The `$skip` and `$limit` stages of a finder.
+    fn build_pagination(options: &ConnectionOptions, model_name: &str, value: &Value) -> Result<Vec<Document>> {
+        let mut retval = vec![];
+        if let (Some(page_size), Some(page_number)) = (value.get("pageSize"), value.get("pageNumber")) {
+            let skip = (page_number.to_int64().unwrap() - 1) * page_size.to_int64().unwrap();
+            Self::check_skip(options, model_name, skip)?;
+            retval.push(doc!{"$skip": skip});
+            retval.push(Self::build_limit(page_size.to_int64().unwrap()));
+        } else {
+            if let Some(skip) = value.get("skip") {
+                let skip = skip.to_int64().unwrap();
+                Self::check_skip(options, model_name, skip)?;
+                retval.push(doc!{"$skip": skip});
+            }
+            if let Some(take) = value.get("take") {
+                retval.push(Self::build_limit(take.to_int64().unwrap().abs()));
+            }
+        }
+        Ok(retval)
+    }
+
+    /// `$limit` must be positive, a limit of 0 matches nothing instead, e.g.
+    /// for `take: 0` on included relations where no short-circuit applies.
+    fn build_limit(limit: i64) -> Document {
+        if limit == 0 {
+            doc!{"$match": {"$expr": false}}
+        } else {
+            doc!{"$limit": limit}
+        }
+    }
+
+    /// The server reads and discards every skipped document, so deep pages
+    /// are slow. Warns about skips beyond `skip_warning_threshold` and
+    /// refuses those beyond `max_skip`.
+    fn check_skip(options: &ConnectionOptions, model_name: &str, skip: i64) -> Result<()> {
+        if let Some(max_skip) = options.max_skip {
+            if skip > max_skip {
+                return Err(Error::new(format!("skipping {} records of {} exceeds the maximum of {}, paginate by cursor instead", skip, model_name, max_skip)));
+            }
+        }
+        if let Some(threshold) = options.skip_warning_threshold {
+            if skip > threshold {
+                options.warn(&format!("warning: skipping {} records of {} scans all of them, consider paginating by cursor", skip, model_name));
+            }
+        }
+        Ok(())
+    }
+
+    /// `{ field: { skip, take } }` keeps only a window of each array field.
+    fn build_slice(model: &Model, slice: &Value) -> Result<Document> {
+        let mut retval = doc!{};
+        for (key, window) in slice.as_dictionary().unwrap() {
+            let Some(field) = model.field(key) else {
+                return Err(Error::new(format!("slice key `{}` is not a field of model `{}`", key, model.name())));
+            };
+            let column_name = field.column_name();
+            let skip = window.get("skip").map_or(0, |s| s.to_int64().unwrap());
+            let take = window.get("take").map(|t| t.to_int64().unwrap());
+            if let Some(expression) = Self::build_slice_expression(column_name, skip, take) {
+                retval.insert(column_name, expression);
+            }
+        }
+        Ok(retval)
+    }
+
+    /// The window of `column_name` skipping `skip` elements and taking
+    /// `take` of them, all the rest without `take`. `None` keeps the array.
+    fn build_slice_expression(column_name: &str, skip: i64, take: Option<i64>) -> Option<Bson> {
+        let array = format!("${column_name}");
+        match take {
+            // `$slice` only takes positive counts, null stays null
+            Some(0) => Some(Bson::Document(doc!{"$cond": [{"$isArray": &array}, [], &array]})),
+            Some(take) => Some(Bson::Document(doc!{"$slice": [&array, skip, take]})),
+            None if skip == 0 => None,
+            None => Some(Bson::Document(doc!{"$slice": [&array, skip, {"$max": [{"$size": {"$ifNull": [&array, []]}}, 1]}]})),
+        }
+    }
+
+    fn build_computed(options: &ConnectionOptions, model: &Model, computed: &Value, aliases: &mut IndexMap<String, String>) -> Result<Document> {
+        let mut retval = doc!{};
+        for (key, expression) in computed.as_dictionary().unwrap() {
+            if model.field(key).is_none() && model.property(key).is_none() {
+                return Err(Error::new(format!("computed key `{}` is not a field or property of model `{}`", key, model.name())));
+            }
+            let alias = format!("{COMPUTED_PREFIX}{key}");
+            retval.insert(alias.clone(), Self::build_computed_expression(options, expression)?);
+            aliases.insert(alias, key.clone());
+        }
+        Ok(retval)
+    }
+
+    /// `{ relation: { into: "key", where } }` counts the related records
+    /// matching `where` and decodes the number onto the given field or
+    /// property, without fetching the related records.
+    pub(crate) fn build_relation_counts(options: &ConnectionOptions, namespace: &Namespace, model: &Model, relation_count: &Value, aliases: &mut IndexMap<String, String>) -> Result<Vec<Document>> {
+        let mut retval = vec![];
+        for (relation_name, count) in relation_count.as_dictionary().unwrap() {
+            let Some(relation) = model.relation(relation_name) else {
+                return Err(Error::new(format!("relation `{}` is not defined on model `{}`", relation_name, model.name())));
+            };
+            if relation.has_join_table() {
+                return Err(Error::new(format!("counting relation `{}` through a join table is not supported", relation_name)));
+            }
+            let Some(into) = count.get("into").and_then(|into| into.as_str()) else {
+                return Err(Error::new(format!("count of relation `{}` requires a key to decode into", relation_name)));
+            };
+            if model.field(into).is_none() && model.property(into).is_none() {
+                return Err(Error::new(format!("count key `{}` is not a field or property of model `{}`", into, model.name())));
+            }
+            let alias = format!("{COMPUTED_PREFIX}{into}");
+            let inner_finder = match count.get("where") {
+                Some(r#where) => teon!({"where": r#where}),
+                None => teon!({}),
+            };
+            let lookups = Self::build_lookup_without_join_table(options, namespace, model, &alias, relation, &inner_finder)?;
+            retval.extend(Self::build_count_of_lookup(lookups, &alias)?);
+            aliases.insert(alias, into.to_owned());
         }
         Ok(retval)
     }
 
-    fn build_select(model: &Model, select: &Value, distinct: Option<&Value>) -> Result<Document> {
+    /// Turn the stages looking up related records into `alias` into ones
+    /// counting them. A count's finder has no `take`, so the lookup is a
+    /// single stage.
+    fn build_count_of_lookup(mut lookups: Vec<Document>, alias: &str) -> Result<Vec<Document>> {
+        if lookups.len() != 1 {
+            return Err(Error::new(format!("count `{}` expects a single lookup stage, but got {}", alias, lookups.len())));
+        }
+        let mut lookup = lookups.remove(0);
+        let Ok(pipeline) = lookup.get_document_mut("$lookup").and_then(|lookup| lookup.get_array_mut("pipeline")) else {
+            return Err(Error::new(format!("count `{}` expects a pipeline lookup", alias)));
+        };
+        pipeline.push(Bson::Document(doc!{"$count": "count"}));
+        Ok(vec![
+            lookup,
+            doc!{"$set": {alias: {"$ifNull": [{"$arrayElemAt": [format!("${alias}.count"), 0]}, 0]}}},
+        ])
+    }
+
+    /// `include: { relation: { keyedInto: "key", keyBy: "field" } }` decodes
+    /// the related records onto the given dictionary field or property, keyed
+    /// by `keyBy` which defaults to their primary field. Of records sharing a
+    /// key, the last in the relation's `orderBy` wins.
+    fn build_keyed_relation(namespace: &Namespace, model: &Model, relation_name: &str, relation_include: &Value, into: &Value, aliases: &mut IndexMap<String, String>) -> Result<Vec<Document>> {
+        let relation = model.relation(relation_name).unwrap();
+        let Some(into) = into.as_str() else {
+            return Err(Error::new(format!("keyedInto of relation `{}` requires a key to decode onto", relation_name)));
+        };
+        if model.field(into).is_none() && model.property(into).is_none() {
+            return Err(Error::new(format!("keyedInto key `{}` is not a field or property of model `{}`", into, model.name())));
+        }
+        let related_model = namespace.model_at_path(&relation.model_path()).unwrap();
+        let key_by = match relation_include.get("keyBy") {
+            Some(key_by) => key_by.as_str().unwrap().to_owned(),
+            None => related_model.primary_index().unwrap().keys().first().unwrap().to_owned(),
+        };
+        let Some(key_field) = related_model.field(&key_by) else {
+            return Err(Error::new(format!("keyBy `{}` is not a field of model `{}`", key_by, related_model.name())));
+        };
+        let alias = format!("{COMPUTED_PREFIX}{into}");
+        aliases.insert(alias.clone(), into.to_owned());
+        Ok(Self::keyed_relation_stages(relation_name, key_field.column_name(), &alias))
+    }
+
+    /// Replace the looked up `relation_name` array by a document under
+    /// `alias`, keyed by the string of each record's `key_column`.
+    /// `$arrayToObject` keeps the last of records sharing a key.
+    fn keyed_relation_stages(relation_name: &str, key_column: &str, alias: &str) -> Vec<Document> {
+        vec![
+            doc!{"$set": {alias: {"$arrayToObject": {"$map": {
+                "input": format!("${relation_name}"),
+                "in": {"k": {"$toString": format!("$$this.{}", key_column)}, "v": "$$this"},
+            }}}}},
+            doc!{"$unset": relation_name},
+        ]
+    }
+
+    /// `{ indexKey: "key", recordId: "key" }` decodes the `$meta` values of
+    /// each document onto the given field or property, for diagnostics.
+    fn build_meta(model: &Model, meta: &Value, aliases: &mut IndexMap<String, String>) -> Result<Document> {
+        let mut retval = doc!{};
+        for (name, key) in meta.as_dictionary().unwrap() {
+            if !["indexKey", "recordId"].contains(&name.as_str()) {
+                return Err(Error::new(format!("unsupported meta `{}`", name)));
+            }
+            let Some(key) = key.as_str() else {
+                return Err(Error::new(format!("meta `{}` requires a key to decode onto", name)));
+            };
+            if model.field(key).is_none() && model.property(key).is_none() {
+                return Err(Error::new(format!("meta key `{}` is not a field or property of model `{}`", key, model.name())));
+            }
+            let alias = format!("{COMPUTED_PREFIX}{key}");
+            retval.insert(alias.clone(), doc!{"$meta": name});
+            aliases.insert(alias, key.to_owned());
+        }
+        Ok(retval)
+    }
+
+    /// `idTimestamp: "key"` decodes the generation time embedded in object id
+    /// `_id`s onto the given datetime field or property, for models without
+    /// an explicit creation time. Records with other `_id`s leave it unset.
+    fn build_id_timestamp(model: &Model, key: &Value, aliases: &mut IndexMap<String, String>) -> Result<Document> {
+        let Some(key) = key.as_str() else {
+            return Err(Error::new("idTimestamp requires a key to decode onto"));
+        };
+        let r#type = match (model.field(key), model.property(key)) {
+            (Some(field), _) => field.r#type(),
+            (None, Some(property)) => property.r#type(),
+            (None, None) => return Err(Error::new(format!("idTimestamp key `{}` is not a field or property of model `{}`", key, model.name()))),
+        };
+        if !matches!(r#type.unwrap_optional(), Type::DateTime) {
+            return Err(Error::new(format!("idTimestamp key `{}` of model `{}` is not a datetime", key, model.name())));
+        }
+        let alias = format!("{COMPUTED_PREFIX}{key}");
+        aliases.insert(alias.clone(), key.to_owned());
+        Ok(doc!{alias: {"$cond": [{"$eq": [{"$type": "$_id"}, "objectId"]}, {"$toDate": "$_id"}, "$$REMOVE"]}})
+    }
+
+    /// `{ switch: { branches: [{ case, then }], default } }` and
+    /// `{ cond: { if, then, else } }` build conditional expressions, `{ sum:
+    /// path }` and likewise `avg`, `max` and `min` aggregate an array, `{
+    /// dateToString: { date, format, timezone } }` formats a date, `{
+    /// function: { body, args } }` runs JavaScript if the connection allows
+    /// it, anything else is an aggregation expression used as is. Without a default, a
+    /// switch matching no branch yields null instead of failing the query.
+    fn build_computed_expression(options: &ConnectionOptions, expression: &Value) -> Result<Bson> {
+        if let Some(switch) = expression.get("switch") {
+            let Some(branches) = switch.get("branches").and_then(|b| b.as_array()) else {
+                return Err(Error::new("switch requires an array of branches"));
+            };
+            let mut built_branches: Vec<Document> = vec![];
+            for branch in branches {
+                let (Some(case), Some(then)) = (branch.get("case"), branch.get("then")) else {
+                    return Err(Error::new("switch branch requires a case and a then"));
+                };
+                built_branches.push(doc!{"case": Self::build_computed_expression(options, case)?, "then": Self::build_computed_expression(options, then)?});
+            }
+            let default = match switch.get("default") {
+                Some(default) => Self::build_computed_expression(options, default)?,
+                None => Bson::Null,
+            };
+            Ok(doc!{"$switch": {"branches": built_branches, "default": default}}.into())
+        } else if let Some(cond) = expression.get("cond") {
+            let (Some(r#if), Some(then)) = (cond.get("if"), cond.get("then")) else {
+                return Err(Error::new("cond requires an if and a then"));
+            };
+            let r#else = match cond.get("else") {
+                Some(r#else) => Self::build_computed_expression(options, r#else)?,
+                None => Bson::Null,
+            };
+            Ok(doc!{"$cond": {"if": Self::build_computed_expression(options, r#if)?, "then": Self::build_computed_expression(options, then)?, "else": r#else}}.into())
+        } else if let Some((operator, array_path)) = ["sum", "avg", "max", "min"].iter().find_map(|operator| expression.get(operator).map(|path| (operator, path))) {
+            // `{ sum: "lineItems.amount" }` aggregates an embedded array, an
+            // empty array sums to 0 and has no average, max or min
+            let Some(array_path) = array_path.as_str() else {
+                return Err(Error::new(format!("{} requires the path of an array", operator)));
+            };
+            Ok(doc!{format!("${operator}"): format!("${array_path}")}.into())
+        } else if let Some(date_to_string) = expression.get("dateToString") {
+            // `{ dateToString: { date: "createdAt", format: "%Y-%m-%d",
+            // timezone: "Asia/Tokyo" } }` formats a date on the server, a
+            // missing or null date yields null
+            let Some(date) = date_to_string.get("date").and_then(|d| d.as_str()) else {
+                return Err(Error::new("dateToString requires the path of a date"));
+            };
+            let mut built = doc!{"date": format!("${date}"), "onNull": Bson::Null};
+            for key in ["format", "timezone"] {
+                if let Some(value) = date_to_string.get(key) {
+                    let Some(value) = value.as_str() else {
+                        return Err(Error::new(format!("dateToString {} must be a string", key)));
+                    };
+                    built.insert(key, value);
+                }
+            }
+            Ok(doc!{"$dateToString": built}.into())
+        } else if let Some(function) = expression.get("function") {
+            // `{ function: { body, args } }` runs JavaScript on the server
+            if !options.allow_server_js {
+                return Err(Error::new("`function` requires `allow_server_js` on the connection"));
+            }
+            let Some(body) = function.get("body").and_then(|b| b.as_str()) else {
+                return Err(Error::new("function requires a JavaScript body"));
+            };
+            let args = match function.get("args") {
+                Some(args) => teon_value_to_bson(args)?,
+                None => Bson::Array(vec![]),
+            };
+            Ok(doc!{"$function": {"body": body, "args": args, "lang": "js"}}.into())
+        } else {
+            teon_value_to_bson(expression)
+        }
+    }
+
+    /// Lazy fields are only fetched when selected explicitly.
+    fn build_select(options: &ConnectionOptions, model: &Model, select: &Value, distinct: Option<&Value>) -> Result<Document> {
         let map = select.as_dictionary().unwrap();
         let true_keys: Vec<&str> = map.iter().filter(|(_k, v)| v.as_bool().unwrap() == true).map(|(k, _)| k.as_str()).collect();
         let false_keys: Vec<&str> = map.iter().filter(|(_k, v)| v.as_bool().unwrap() == false).map(|(k, _)| k.as_str()).collect();
@@ -301,7 +718,8 @@ impl Aggregation {
         let mut keys: HashSet<String> = HashSet::new();
         let save_unmentioned_keys = true_keys.is_empty();
         model.cache().all_keys.iter().for_each(|k| {
-            let save = primary_field_names.contains(k) || (!false_keys.contains(&k.as_str()) && (true_keys.contains(&k.as_str()) || save_unmentioned_keys));
+            let save_unmentioned = save_unmentioned_keys && !options.is_lazy_field(model, k);
+            let save = primary_field_names.contains(k) || (!false_keys.contains(&k.as_str()) && (true_keys.contains(&k.as_str()) || save_unmentioned));
             if save {
                 if let Some(field) = model.field(k) {
                     let column_name = field.column_name();
@@ -328,6 +746,46 @@ impl Aggregation {
         Ok(result)
     }
 
+    /// An exclusion projection of the lazy fields of `model`, fetched only
+    /// when selected. They are left unset on decoded objects.
+    fn build_lazy_exclusion(options: &ConnectionOptions, model: &Model) -> Document {
+        let mut retval = doc!{};
+        if options.lazy_fields.is_empty() {
+            return retval;
+        }
+        for field in model.fields().values() {
+            if options.is_lazy_field(model, field.name()) {
+                retval.insert(field.column_name(), 0);
+            }
+        }
+        retval
+    }
+
+    /// An exclusion projection, which MongoDB doesn't allow to be mixed with
+    /// included fields. Primary fields are needed to identify records.
+    fn build_omit(model: &Model, omit: &Value) -> Result<Document> {
+        let primary_field_names = model.primary_index().unwrap().keys();
+        let mut result = doc!{};
+        for key in Self::omitted_keys(omit)? {
+            if primary_field_names.contains(&key.to_string()) {
+                return Err(Error::new(format!("primary field `{}` cannot be omitted", key)));
+            }
+            let Some(field) = model.field(key) else {
+                return Err(Error::new(format!("omitted key `{}` is not a field of model `{}`", key, model.name())));
+            };
+            result.insert(field.column_name(), 0);
+        }
+        Ok(result)
+    }
+
+    /// The keys of an `omit`, which is an array of strings.
+    fn omitted_keys(omit: &Value) -> Result<Vec<&str>> {
+        let Some(keys) = omit.as_array() else {
+            return Err(Error::new("`omit` takes an array of keys"));
+        };
+        keys.iter().map(|key| key.as_str().ok_or_else(|| Error::new("`omit` takes an array of keys"))).collect()
+    }
+
     fn build_order_by(model: &Model, order_by: &Value, reverse: bool) -> Result<Document> {
         let mut retval = doc!{};
         for sort in order_by.as_array().unwrap().iter() {
@@ -345,37 +803,70 @@ impl Aggregation {
         Ok(retval)
     }
 
-    fn build_where(namespace: &Namespace, model: &Model, value: &Value) -> Result<Document> {
+    /// Like `build_where`, for filters used outside a pipeline, e.g. by
+    /// updates. Relation conditions need the relations looked up first,
+    /// so they are rejected.
+    pub(crate) fn build_scalar_where(options: &ConnectionOptions, namespace: &Namespace, model: &Model, value: &Value) -> Result<Document> {
+        Self::check_scalar_where(model, value)?;
+        Self::build_where(options, namespace, model, value)
+    }
+
+    fn check_scalar_where(model: &Model, value: &Value) -> Result<()> {
+        let Some(value_map) = value.as_dictionary() else {
+            return Err(Error::new("filter must be a dictionary"));
+        };
+        for (key, value) in value_map {
+            match key.as_str() {
+                "AND" | "OR" | "NOR" => for val in value.as_array().into_iter().flatten() {
+                    Self::check_scalar_where(model, val)?;
+                },
+                "NOT" => Self::check_scalar_where(model, value)?,
+                _ => if model.relation(key).is_some() {
+                    return Err(Error::new(format!("relation `{}` can't be filtered on here, only fields of `{}` can", key, model.name())));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn build_where(options: &ConnectionOptions, namespace: &Namespace, model: &Model, value: &Value) -> Result<Document> {
         let value_map = value.as_dictionary().unwrap();
         let mut retval = doc!{};
         for (key, value) in value_map.iter() {
             let key = key.as_str();
             match key {
-                "AND" => {
-                    let mut vals: Vec<Document> = vec![];
-                    for val in value.as_array().unwrap() {
-                        vals.push(Self::build_where(namespace, model, val)?);
-                    }
-                    retval.insert("$and", vals);
-                }
-                "OR" => {
-                    let mut vals: Vec<Document> = vec![];
-                    for val in value.as_array().unwrap() {
-                        vals.push(Self::build_where(namespace, model, val)?);
-                    }
-                    retval.insert("$or", vals);
+                "AND" | "OR" | "NOR" => {
+                    let Some(conditions) = value.as_array() else {
+                        return Err(Error::new(format!("`{}` takes an array of conditions", key)));
+                    };
+                    let conditions = conditions.iter().map(|val| Self::build_where(options, namespace, model, val)).collect::<Result<Vec<Document>>>()?;
+                    let (operator, conditions) = Self::build_logical(key, conditions);
+                    retval.insert(operator, conditions);
                 }
                 "NOT" => {
-                    retval.insert("$nor", vec![Self::build_where(namespace, model, value)?]);
+                    let (operator, conditions) = Self::build_logical(key, vec![Self::build_where(options, namespace, model, value)?]);
+                    retval.insert(operator, conditions);
+                }
+                "$text" => {
+                    return Err(Error::new("`$text` is only supported at the top level of `where`"));
+                }
+                "$where" => {
+                    if !options.allow_unsafe_where {
+                        return Err(Error::new("`$where` requires `allow_unsafe_where` on the connection"));
+                    }
+                    let Some(predicate) = value.as_str() else {
+                        return Err(Error::new("`$where` must be a JavaScript string"));
+                    };
+                    retval.insert("$where", predicate);
                 }
                 _ => {
                     if let Some(field) = model.field(key) {
                         let column_name = field.column_name();
-                        retval.insert(column_name, Self::build_where_item(model, field.r#type(), field.is_optional(), value)?);
+                        retval.insert(column_name, Self::build_where_item(options, field.r#type(), field.is_optional(), value)?);
                     } else if let Some(relation) = model.relation(key) {
                         let relation_model = namespace.model_at_path(&relation.model_path()).unwrap();
                         let (command, inner_where) = Input::key_value(value.as_dictionary().unwrap());
-                        let _inner_where = Self::build_where(namespace, relation_model, inner_where)?;
+                        let _inner_where = Self::build_where(options, namespace, relation_model, inner_where)?;
                         match command {
                             "none" | "isNot" => {
                                 retval.insert(key, doc!{"$size": 0});
@@ -388,6 +879,11 @@ impl Aggregation {
                             }
                             _ => {}
                         }
+                    } else if let Some(index) = Self::compound_unique_index(model, key) {
+                        for (field_name, component) in Self::compound_unique_components(key, index.keys(), value)? {
+                            let field = model.field(field_name).unwrap();
+                            retval.insert(field.column_name(), Self::build_where_item(options, field.r#type(), field.is_optional(), component)?);
+                        }
                     }
                 }
             }
@@ -395,11 +891,76 @@ impl Aggregation {
         Ok(retval)
     }
 
-    fn build_where_item(_model: &Model, _type: &Type, _optional: bool, value: &Value) -> Result<Bson> {
+    /// Split the `$text` search off `r#where` into the `$match` stage the
+    /// server requires first, along with the remaining conditions.
+    fn hoist_text_search(r#where: &Value) -> Result<Option<(Document, Value)>> {
+        let Some(text_search) = r#where.get("$text") else {
+            return Ok(None);
+        };
+        let stage = doc!{"$match": {"$text": Self::build_text_search(text_search)?}};
+        let rest = Value::Dictionary(r#where.as_dictionary().unwrap().iter().filter(|(k, _)| k.as_str() != "$text").map(|(k, v)| (k.clone(), v.clone())).collect());
+        Ok(Some((stage, rest)))
+    }
+
+    /// `{ search, language, caseSensitive, diacriticSensitive }` searches the
+    /// text index of the collection.
+    fn build_text_search(text_search: &Value) -> Result<Document> {
+        let Some(search) = text_search.get("search").and_then(|s| s.as_str()) else {
+            return Err(Error::new("`$text` requires a search string"));
+        };
+        let mut retval = doc!{"$search": search};
+        if let Some(language) = text_search.get("language") {
+            let Some(language) = language.as_str() else {
+                return Err(Error::new("`$text` language must be a string"));
+            };
+            retval.insert("$language", language);
+        }
+        for key in ["caseSensitive", "diacriticSensitive"] {
+            if let Some(sensitive) = text_search.get(key) {
+                let Some(sensitive) = sensitive.as_bool() else {
+                    return Err(Error::new(format!("`$text` {} must be a bool", key)));
+                };
+                retval.insert(format!("${key}"), sensitive);
+            }
+        }
+        Ok(retval)
+    }
+
+    /// The values of `{ a_b: { a, b } }`, selecting a record by a compound
+    /// unique key, in the order of the index's fields.
+    fn compound_unique_components<'a>(key: &str, field_names: impl IntoIterator<Item = &'a String>, value: &'a Value) -> Result<Vec<(&'a String, &'a Value)>> {
+        let Some(components) = value.as_dictionary() else {
+            return Err(Error::new(format!("compound unique key `{}` requires a dictionary", key)));
+        };
+        field_names.into_iter().map(|field_name| match components.get(field_name) {
+            Some(component) => Ok((field_name, component)),
+            None => Err(Error::new(format!("compound unique key `{}` lacks `{}`", key, field_name))),
+        }).collect()
+    }
+
+    /// The unique index named `key` or whose field names joined with `_` are
+    /// `key`. Duplicate key errors are reported for the same indexes.
+    fn compound_unique_index<'a>(model: &'a Model, key: &str) -> Option<&'a Index> {
+        model.indexes().values().find(|index| {
+            (index.r#type() == IndexType::Unique || index.r#type() == IndexType::Primary) && index.keys().len() > 1 && (index.name() == key || index.keys().join("_") == key)
+        })
+    }
+
+    /// The operator combining `conditions` by the logical `key`.
+    fn build_logical(key: &str, conditions: Vec<Document>) -> (&'static str, Vec<Document>) {
+        match key {
+            "AND" => ("$and", conditions),
+            "OR" => ("$or", conditions),
+            // `$not` can't wrap a whole filter like `$or`, `$nor` of one can
+            _ => ("$nor", conditions),
+        }
+    }
+
+    fn build_where_item(options: &ConnectionOptions, r#type: &Type, _optional: bool, value: &Value) -> Result<Bson> {
         if let Some(map) = value.as_dictionary() {
             Ok(Bson::Document(map.iter().filter(|(k, _)| k.as_str() != "mode").map(|(k, v)| {
                 let k = k.as_str();
-                match k {
+                Ok(match k {
                     "startsWith" => {
                         let bson_regex = BsonRegex {
                             pattern: "^".to_string() + &*regex::escape(v.as_str().unwrap()),
@@ -435,16 +996,60 @@ impl Aggregation {
                     "isEmpty" => {
                         ("$size".to_string(), Bson::from(0))
                     },
-                    _ => (Self::build_where_key(k).as_str().unwrap().to_string(), teon_value_to_bson(v))
-                }
-            }).collect()))
+                    "all" => {
+                        ("$all".to_string(), Self::build_all(options, r#type, v)?)
+                    },
+                    // `not: { contains: .. }`, `not: { in: [..] }` negate an operator
+                    "not" if v.is_dictionary() => {
+                        ("$not".to_string(), Self::build_where_item(options, r#type, _optional, v)?)
+                    },
+                    "gt" | "lte" if matches!((r#type.unwrap_optional(), v), (Type::DateTime, Value::Date(_))) => {
+                        Self::build_end_of_day_bound(k, v)?
+                    },
+                    _ => (Self::build_where_key(k)?.to_string(), BsonCoder::encode_filter_value(options, r#type, v)?)
+                })
+            }).collect::<Result<Vec<(String, Bson)>>>()?.into_iter().collect()))
         } else {
-            Ok(teon_value_to_bson(value))
+            BsonCoder::encode_filter_value(options, r#type, value)
         }
     }
 
-    fn build_where_key(key: &str) -> Bson {
-        Bson::String(match key {
+    /// A date bound of a datetime field covers the whole day, so `lte` and
+    /// `gt` compare against the start of the next day.
+    fn build_end_of_day_bound(key: &str, value: &Value) -> Result<(String, Bson)> {
+        let Some(next_day) = value.as_date().and_then(|date| date.succ_opt()) else {
+            return Err(Error::new("date bound has no following day"));
+        };
+        let next_day = Value::Date(next_day);
+        let operator = if key == "lte" { "$lt" } else { "$gte" };
+        Ok((operator.to_string(), teon_value_to_bson(&next_day)?))
+    }
+
+    /// Elements are encoded like `$in` values, dictionary elements are treated
+    /// as conditions on embedded documents and wrapped in `$elemMatch`.
+    fn build_all(options: &ConnectionOptions, r#type: &Type, value: &Value) -> Result<Bson> {
+        let Some(elements) = value.as_array() else {
+            return Err(Error::new("`all` takes an array"));
+        };
+        let element_type = match r#type.unwrap_optional() {
+            Type::Array(inner) => inner.unwrap_optional(),
+            r#type => r#type,
+        };
+        Ok(Bson::Array(elements.iter().map(|element| {
+            if let Some(conditions) = element.as_dictionary() {
+                let mut elem_match = doc!{};
+                for (key, condition) in conditions {
+                    elem_match.insert(key, Self::build_where_item(options, &Type::Any, true, condition)?);
+                }
+                Ok(Bson::Document(doc!{"$elemMatch": elem_match}))
+            } else {
+                BsonCoder::encode_filter_value(options, element_type, element)
+            }
+        }).collect::<Result<Vec<Bson>>>()?))
+    }
+
+    fn build_where_key(key: &str) -> Result<&'static str> {
+        Ok(match key {
             "equals" => "$eq",
             "not" => "$ne",
             "gt" => "$gt",
@@ -457,27 +1062,27 @@ impl Aggregation {
             "hasEvery" => "$all",
             "hasSome" => "$in",
             "length" => "$size",
-            _ => panic!("Unhandled key.")
-        }.to_owned())
+            _ => return Err(Error::new(format!("unknown filter operator `{}`", key))),
+        })
     }
 
-    fn build_lookups(namespace: &Namespace, model: &Model, include: &Value) -> Result<Vec<Document>> {
+    fn build_lookups(options: &ConnectionOptions, namespace: &Namespace, model: &Model, include: &Value) -> Result<Vec<Document>> {
         let include = include.as_dictionary().unwrap();
         let mut retval: Vec<Document> = vec![];
         for (key, value) in include {
             let relation = model.relation(key).unwrap();
             if (value.is_bool() && (value.as_bool().unwrap() == true)) || (value.is_dictionary()) {
                 if relation.has_join_table() {
-                    retval.extend(Self::build_lookup_with_join_table(namespace, model, key, relation, value)?)
+                    retval.extend(Self::build_lookup_with_join_table(options, namespace, model, key, relation, value)?)
                 } else {
-                    retval.extend(Self::build_lookup_without_join_table(namespace, model, key, relation, value)?)
+                    retval.extend(Self::build_lookup_without_join_table(options, namespace, model, key, relation, value)?)
                 }
             }
         }
         Ok(retval)
     }
 
-    fn build_lookup_with_join_table(namespace: &Namespace, model: &Model, _key: &str, relation: &Relation, value: &Value) -> Result<Vec<Document>> {
+    fn build_lookup_with_join_table(options: &ConnectionOptions, namespace: &Namespace, model: &Model, _key: &str, relation: &Relation, value: &Value) -> Result<Vec<Document>> {
         let mut retval = vec![];
         let join_model = namespace.model_at_path(&relation.through_path().unwrap()).unwrap();
         let local_relation_on_join_table = join_model.relation(relation.local().unwrap()).unwrap();
@@ -492,16 +1097,16 @@ impl Aggregation {
             let jt_column_name = join_model.field(jt_field).unwrap().column_name();
             let local_column_name = model.field(local_field).unwrap().column_name();
             outer_let_value.insert(jt_column_name, format!("${local_column_name}"));
-            outer_eq_values.push(doc! {"$eq": [format!("${jt_column_name}"), format!("$${jt_column_name}")]});
+            outer_eq_values.push(Self::build_join_eq(options, format!("${jt_column_name}"), format!("$${jt_column_name}")));
         }
         for (jt_field, foreign_field) in foreign_relation_on_join_table.iter() {
             let jt_column_name = join_model.field(jt_field).unwrap().column_name();
             let foreign_column_name = model.field(foreign_field).unwrap().column_name();
             inner_let_value.insert(jt_column_name, format!("${jt_column_name}"));
-            inner_eq_values.push(doc! {"$eq": [format!("${foreign_column_name}"), format!("$${jt_column_name}")]});
+            inner_eq_values.push(Self::build_join_eq(options, format!("${foreign_column_name}"), format!("$${jt_column_name}")));
         }
         let mut original_inner_pipeline = if value.is_dictionary() {
-            Self::build(namespace, opposite_model, value)?
+            Self::build(options, namespace, opposite_model, value)?
         } else {
             vec![]
         };
@@ -588,7 +1193,7 @@ impl Aggregation {
         }
         let mut target = doc! {
             "$lookup": {
-                "from": join_model.table_name(),
+                "from": options.collection_name(join_model),
                 "as": relation.name(),
                 "let": outer_let_value,
                 "pipeline": [{
@@ -599,7 +1204,7 @@ impl Aggregation {
                     }
                 }, {
                     "$lookup": {
-                        "from": opposite_model.table_name(),
+                        "from": options.collection_name(opposite_model),
                         "as": relation.name(),
                         "let": inner_let_value,
                         "pipeline": original_inner_pipeline
@@ -646,21 +1251,48 @@ impl Aggregation {
         Ok(retval)
     }
 
-    fn build_lookup_without_join_table(namespace: &Namespace, model: &Model, key: &str, relation: &Relation, value: &Value) -> Result<Vec<Document>> {
+    /// The `let` variables and `$expr` condition of a lookup joining on
+    /// `on` instead of the foreign key.
+    fn build_custom_join(custom_let: Option<&Value>, on: &Value) -> Result<(Document, Document)> {
+        let let_value = match custom_let.map(teon_value_to_bson).transpose()? {
+            None => doc!{},
+            Some(Bson::Document(let_value)) => let_value,
+            Some(_) => return Err(Error::new("`let` must be a dictionary of variables")),
+        };
+        // the server only accepts variables starting with a lowercase letter
+        if let Some(name) = let_value.keys().find(|name| !name.starts_with(|c: char| c.is_ascii_lowercase()) || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+            return Err(Error::new(format!("`{}` is not a valid `let` variable name", name)));
+        }
+        match teon_value_to_bson(on)? {
+            Bson::Document(on) if !on.is_empty() => Ok((let_value, on)),
+            _ => Err(Error::new("`on` must be an expression dictionary")),
+        }
+    }
+
+    fn build_lookup_without_join_table(options: &ConnectionOptions, namespace: &Namespace, model: &Model, key: &str, relation: &Relation, value: &Value) -> Result<Vec<Document>> {
         let mut retval = vec![];
         let mut let_value = doc!{};
         let mut eq_values: Vec<Document> = vec![];
         let (opposite_model, _opposite_relation) = namespace.opposite_relation(relation);
-        for (field, reference) in relation.iter() {
-            let _field_name = model.field(field).unwrap().name();
-            let field_column_name = model.field(field).unwrap().column_name();
-            let reference_name = opposite_model.field(reference).unwrap().name();
-            let reference_column_name = opposite_model.field(reference).unwrap().column_name();
-            let_value.insert(reference_name, format!("${field_column_name}"));
-            eq_values.push(doc!{"$eq": [format!("${reference_column_name}"), format!("$${reference_name}")]});
+        if let Some(on) = value.get("on") {
+            // join on an arbitrary `$expr` condition instead of the foreign key
+            let (custom_let, custom_on) = Self::build_custom_join(value.get("let"), on)?;
+            let_value = custom_let;
+            eq_values.push(custom_on);
+        } else if value.get("let").is_some() {
+            return Err(Error::new(format!("`let` of relation `{}` requires `on`", key)));
+        } else {
+            for (field, reference) in relation.iter() {
+                let _field_name = model.field(field).unwrap().name();
+                let field_column_name = model.field(field).unwrap().column_name();
+                let reference_name = opposite_model.field(reference).unwrap().name();
+                let reference_column_name = opposite_model.field(reference).unwrap().column_name();
+                let_value.insert(reference_name, format!("${field_column_name}"));
+                eq_values.push(Self::build_join_eq(options, format!("${reference_column_name}"), format!("$${reference_name}")));
+            }
         }
         let mut inner_pipeline = if value.is_dictionary() {
-            Self::build(namespace, opposite_model, value)?
+            Self::build(options, namespace, opposite_model, value)?
         } else {
             vec![]
         };
@@ -689,7 +1321,7 @@ impl Aggregation {
         }
         let lookup = doc!{
             "$lookup": {
-                "from": opposite_model.table_name(),
+                "from": options.collection_name(opposite_model),
                 "as": key,
                 "let": let_value,
                 "pipeline": inner_pipeline
@@ -702,6 +1334,16 @@ impl Aggregation {
         Ok(retval)
     }
 
+    /// With tolerant object ids, keys stored as an object id on one side and
+    /// as its hex string on the other still join. This bypasses indexes.
+    fn build_join_eq(options: &ConnectionOptions, lhs: String, rhs: String) -> Document {
+        if options.tolerant_object_ids {
+            doc!{"$eq": [{"$toString": lhs}, {"$toString": rhs}]}
+        } else {
+            doc!{"$eq": [lhs, rhs]}
+        }
+    }
+
     fn build_unsets_for_relation_where(model: &Model, r#where: &Value) -> Result<Vec<Document>> {
         let r#where = r#where.as_dictionary().unwrap();
         let mut retval: Vec<Document> = vec![];
@@ -713,7 +1355,7 @@ impl Aggregation {
         Ok(retval)
     }
 
-    fn build_lookups_for_relation_where(namespace: &Namespace, model: &Model, r#where: &Value) -> Result<Vec<Document>> {
+    fn build_lookups_for_relation_where(options: &ConnectionOptions, namespace: &Namespace, model: &Model, r#where: &Value) -> Result<Vec<Document>> {
         let r#where = r#where.as_dictionary().unwrap();
         let mut include_input = IndexMap::new();
         for (key, value) in r#where.iter() {
@@ -744,7 +1386,7 @@ impl Aggregation {
             }
         }
         Ok(if !include_input.is_empty() {
-            Self::build_lookups(namespace, model, &Value::Dictionary(include_input))?
+            Self::build_lookups(options, namespace, model, &Value::Dictionary(include_input))?
         } else {
             vec![]
         })
@@ -766,3 +1408,240 @@ impl Aggregation {
         Value::Array(vec)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bson::{doc, Bson};
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use teo_parser::r#type::Type;
+    use teo_runtime::{teon, Value};
+    use crate::connector::ConnectionOptions;
+    use super::{Aggregation, COMPUTED_PREFIX};
+
+    #[test]
+    fn custom_join_is_built() {
+        let (let_value, on) = Aggregation::build_custom_join(Some(&teon!({"authorId": "$authorId"})), &teon!({"$eq": ["$_id", "$$authorId"]})).unwrap();
+        assert_eq!(let_value, doc!{"authorId": "$authorId"});
+        assert_eq!(on, doc!{"$eq": ["$_id", "$$authorId"]});
+    }
+
+    #[test]
+    fn custom_join_rejects_invalid_let() {
+        assert!(Aggregation::build_custom_join(Some(&teon!(["$authorId"])), &teon!({"$eq": ["$_id", "$$a"]})).is_err());
+        assert!(Aggregation::build_custom_join(Some(&teon!({"AuthorId": "$authorId"})), &teon!({"$eq": ["$_id", "$$AuthorId"]})).is_err());
+        assert!(Aggregation::build_custom_join(Some(&teon!({"$id": "$authorId"})), &teon!({"$eq": ["$_id", "$$id"]})).is_err());
+    }
+
+    #[test]
+    fn custom_join_rejects_invalid_on() {
+        assert!(Aggregation::build_custom_join(None, &teon!("$_id")).is_err());
+        assert!(Aggregation::build_custom_join(None, &teon!({})).is_err());
+    }
+
+    #[test]
+    fn slice_without_take_keeps_the_rest() {
+        assert_eq!(Aggregation::build_slice_expression("tags", 0, None), None);
+        assert_eq!(Aggregation::build_slice_expression("tags", 2, None), Some(Bson::Document(doc!{"$slice": ["$tags", 2_i64, {"$max": [{"$size": {"$ifNull": ["$tags", []]}}, 1]}]})));
+    }
+
+    #[test]
+    fn slice_taking_nothing_is_empty() {
+        assert_eq!(Aggregation::build_slice_expression("tags", 2, Some(0)), Some(Bson::Document(doc!{"$cond": [{"$isArray": "$tags"}, [], "$tags"]})));
+    }
+
+    #[test]
+    fn slice_window() {
+        assert_eq!(Aggregation::build_slice_expression("tags", 2, Some(3)), Some(Bson::Document(doc!{"$slice": ["$tags", 2_i64, 3_i64]})));
+    }
+
+    #[test]
+    fn nested_aliases_map_prefixed_columns_to_their_targets() {
+        let document = doc!{"_id": 1, "name": "a", format!("{COMPUTED_PREFIX}postsCount"): 2, format!("{COMPUTED_PREFIX}slug"): "a"};
+        let aliases = Aggregation::nested_aliases(&document);
+        assert_eq!(aliases.len(), 2);
+        assert_eq!(aliases.get(&format!("{COMPUTED_PREFIX}postsCount")).map(String::as_str), Some("postsCount"));
+        assert_eq!(aliases.get(&format!("{COMPUTED_PREFIX}slug")).map(String::as_str), Some("slug"));
+    }
+
+    #[test]
+    fn keyed_relations_are_mapped_by_their_key() {
+        let alias = format!("{COMPUTED_PREFIX}postsById");
+        assert_eq!(Aggregation::keyed_relation_stages("posts", "_id", &alias), vec![
+            doc!{"$set": {&alias: {"$arrayToObject": {"$map": {
+                "input": "$posts",
+                "in": {"k": {"$toString": "$$this._id"}, "v": "$$this"},
+            }}}}},
+            doc!{"$unset": "posts"},
+        ]);
+    }
+
+    #[test]
+    fn ids_are_collected_per_group() {
+        let (mut group, mut set, mut unset) = (doc!{"_id": {"category_id": "$category_id"}}, doc!{}, vec![]);
+        Aggregation::insert_group_set_unset_for_column(&mut group, &mut set, &mut unset, "productId", "product_id", "push", false);
+        Aggregation::insert_group_set_unset_for_column(&mut group, &mut set, &mut unset, "tag", "tag", "addToSet", false);
+        assert_eq!(group, doc!{
+            "_id": {"category_id": "$category_id"},
+            "_push_product_id": {"$push": "$product_id"},
+            "_addToSet_tag": {"$addToSet": "$tag"},
+        });
+        assert_eq!(set, doc!{"_push.productId": "$_push_product_id", "_addToSet.tag": "$_addToSet_tag"});
+        assert_eq!(unset, vec!["_push_product_id".to_owned(), "_addToSet_tag".to_owned()]);
+    }
+
+    #[test]
+    fn text_searches_are_hoisted_off_other_conditions() {
+        let r#where = teon!({"status": "published", "$text": {"search": "mongo", "language": "en", "caseSensitive": true}});
+        let (stage, rest) = Aggregation::hoist_text_search(&r#where).unwrap().unwrap();
+        assert_eq!(stage, doc!{"$match": {"$text": {"$search": "mongo", "$language": "en", "$caseSensitive": true}}});
+        let rest = rest.as_dictionary().unwrap();
+        assert_eq!(rest.keys().collect::<Vec<_>>(), vec!["status"]);
+        assert!(Aggregation::hoist_text_search(&teon!({"status": "published"})).unwrap().is_none());
+    }
+
+    #[test]
+    fn text_searches_require_a_search_string() {
+        assert!(Aggregation::hoist_text_search(&teon!({"$text": {"language": "en"}})).is_err());
+        assert!(Aggregation::hoist_text_search(&teon!({"$text": {"search": "mongo", "caseSensitive": "yes"}})).is_err());
+    }
+
+    #[test]
+    fn compound_unique_keys_select_every_component() {
+        let field_names = vec!["email".to_owned(), "tenantId".to_owned()];
+        let value = teon!({"tenantId": 3, "email": "ann@example.com"});
+        let components = Aggregation::compound_unique_components("email_tenantId", &field_names, &value).unwrap();
+        assert_eq!(components.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), vec!["email", "tenantId"]);
+        assert_eq!(components[0].1.as_str(), Some("ann@example.com"));
+        assert_eq!(components[1].1.to_int64(), Some(3));
+    }
+
+    #[test]
+    fn incomplete_compound_unique_keys_are_rejected() {
+        let field_names = vec!["email".to_owned(), "tenantId".to_owned()];
+        assert!(Aggregation::compound_unique_components("email_tenantId", &field_names, &teon!({"email": "ann@example.com"})).is_err());
+        assert!(Aggregation::compound_unique_components("email_tenantId", &field_names, &Value::String("ann@example.com".to_owned())).is_err());
+    }
+
+    #[test]
+    fn pagination_skips_then_limits_to_the_page() {
+        let options = ConnectionOptions::default();
+        let stages = Aggregation::build_pagination(&options, "Post", &teon!({"skip": 20, "take": -10})).unwrap();
+        assert_eq!(stages, vec![doc!{"$skip": 20_i64}, doc!{"$limit": 10_i64}]);
+        let stages = Aggregation::build_pagination(&options, "Post", &teon!({"pageSize": 10, "pageNumber": 3})).unwrap();
+        assert_eq!(stages, vec![doc!{"$skip": 20_i64}, doc!{"$limit": 10_i64}]);
+        assert!(Aggregation::build_pagination(&options, "Post", &teon!({})).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pagination_taking_nothing_matches_nothing() {
+        let options = ConnectionOptions::default();
+        let stages = Aggregation::build_pagination(&options, "Post", &teon!({"skip": 5, "take": 0})).unwrap();
+        assert_eq!(stages, vec![doc!{"$skip": 5_i64}, doc!{"$match": {"$expr": false}}]);
+        let stages = Aggregation::build_pagination(&options, "Post", &teon!({"pageSize": 0, "pageNumber": 1})).unwrap();
+        assert_eq!(stages, vec![doc!{"$skip": 0_i64}, doc!{"$match": {"$expr": false}}]);
+    }
+
+    #[test]
+    fn pagination_rejects_skips_above_the_maximum() {
+        let options = ConnectionOptions { max_skip: Some(100), ..Default::default() };
+        assert!(Aggregation::build_pagination(&options, "Post", &teon!({"skip": 101})).is_err());
+        assert!(Aggregation::build_pagination(&options, "Post", &teon!({"pageSize": 50, "pageNumber": 4})).is_err());
+        assert!(Aggregation::build_pagination(&options, "Post", &teon!({"skip": 100})).is_ok());
+    }
+
+    #[test]
+    fn filtered_lookups_are_counted() {
+        let lookup = doc!{"$lookup": {
+            "from": "comments",
+            "as": "__computed_approvedComments",
+            "let": {"postId": "$_id"},
+            "pipeline": [{"$match": {"approved": true, "$expr": {"$and": [{"$eq": ["$postId", "$$postId"]}]}}}],
+        }};
+        let stages = Aggregation::build_count_of_lookup(vec![lookup], "__computed_approvedComments").unwrap();
+        assert_eq!(stages, vec![
+            doc!{"$lookup": {
+                "from": "comments",
+                "as": "__computed_approvedComments",
+                "let": {"postId": "$_id"},
+                "pipeline": [
+                    {"$match": {"approved": true, "$expr": {"$and": [{"$eq": ["$postId", "$$postId"]}]}}},
+                    {"$count": "count"},
+                ],
+            }},
+            doc!{"$set": {"__computed_approvedComments": {"$ifNull": [{"$arrayElemAt": ["$__computed_approvedComments.count", 0]}, 0]}}},
+        ]);
+    }
+
+    #[test]
+    fn counts_of_several_lookup_stages_are_rejected() {
+        let lookup = doc!{"$lookup": {"from": "comments", "as": "c", "pipeline": []}};
+        let reverse = doc!{"$set": {"c": {"$reverseArray": "$c"}}};
+        assert!(Aggregation::build_count_of_lookup(vec![lookup, reverse], "c").is_err());
+        assert!(Aggregation::build_count_of_lookup(vec![], "c").is_err());
+    }
+
+    #[test]
+    fn omitted_keys_are_read() {
+        assert_eq!(Aggregation::omitted_keys(&teon!(["name", "email"])).unwrap(), vec!["name", "email"]);
+    }
+
+    #[test]
+    fn malformed_omits_are_rejected() {
+        assert!(Aggregation::omitted_keys(&teon!("name")).is_err());
+        assert!(Aggregation::omitted_keys(&teon!(["name", 1])).is_err());
+    }
+
+    #[test]
+    fn inclusive_day_bounds_cover_the_whole_day() {
+        let day = Value::Date(NaiveDate::from_ymd_opt(2024, 3, 10).unwrap());
+        let next_day = Bson::DateTime(bson::DateTime::from_chrono(Utc.with_ymd_and_hms(2024, 3, 11, 0, 0, 0).unwrap()));
+        assert_eq!(Aggregation::build_end_of_day_bound("lte", &day).unwrap(), ("$lt".to_owned(), next_day));
+    }
+
+    #[test]
+    fn exclusive_day_bounds_exclude_the_whole_day() {
+        let day = Value::Date(NaiveDate::from_ymd_opt(2024, 12, 31).unwrap());
+        let next_day = Bson::DateTime(bson::DateTime::from_chrono(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()));
+        assert_eq!(Aggregation::build_end_of_day_bound("gt", &day).unwrap(), ("$gte".to_owned(), next_day));
+    }
+
+    #[test]
+    fn day_bounds_without_a_following_day_are_rejected() {
+        assert!(Aggregation::build_end_of_day_bound("lte", &Value::Date(NaiveDate::MAX)).is_err());
+    }
+
+    #[test]
+    fn nor_of_two_conditions() {
+        let options = ConnectionOptions::default();
+        let conditions = vec![
+            doc!{"status": Aggregation::build_where_item(&options, &Type::String, false, &teon!({"equals": "archived"})).unwrap()},
+            doc!{"views": Aggregation::build_where_item(&options, &Type::Int, false, &teon!({"lt": 10})).unwrap()},
+        ];
+        let (operator, conditions) = Aggregation::build_logical("NOR", conditions);
+        assert_eq!(doc!{operator: conditions}, doc!{"$nor": [{"status": {"$eq": "archived"}}, {"views": {"$lt": 10}}]});
+    }
+
+    #[test]
+    fn not_of_a_whole_filter_is_a_nor_of_one() {
+        let condition = doc!{"$or": [{"status": "archived"}, {"views": 0}]};
+        let (operator, conditions) = Aggregation::build_logical("NOT", vec![condition.clone()]);
+        assert_eq!(operator, "$nor");
+        assert_eq!(conditions, vec![condition]);
+    }
+
+    #[test]
+    fn negated_in() {
+        let options = ConnectionOptions::default();
+        let condition = Aggregation::build_where_item(&options, &Type::String, false, &teon!({"not": {"in": ["a", "b"]}})).unwrap();
+        assert_eq!(condition, Bson::Document(doc!{"$not": {"$in": ["a", "b"]}}));
+    }
+
+    #[test]
+    fn negated_contains_and_comparison() {
+        let options = ConnectionOptions::default();
+        let condition = Aggregation::build_where_item(&options, &Type::String, false, &teon!({"not": {"contains": "a.b"}})).unwrap();
+        assert_eq!(condition, Bson::Document(doc!{"$not": {"$regex": Bson::RegularExpression(bson::Regex { pattern: "a\\.b".to_owned(), options: "".to_owned() })}}));
+        let condition = Aggregation::build_where_item(&options, &Type::Int, false, &teon!({"not": {"gte": 5}})).unwrap();
+        assert_eq!(condition, Bson::Document(doc!{"$not": {"$gte": 5}}));
+    }
+}
@@ -1,26 +1,35 @@
-use bson::Bson;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use bson::{Binary, Bson, Document};
+use bson::spec::BinarySubtype;
+use bson::oid::ObjectId;
 use indexmap::IndexMap;
 use key_path::KeyPath;
 use teo_result::{Error, Result};
 use teo_parser::r#type::Type;
 use teo_runtime::traits::named::Named;
+use teo_runtime::model::field::is_optional::IsOptional;
+use teo_runtime::model::field::typed::Typed;
 use teo_runtime::Value;
 use teo_runtime::model::Model;
 use teo_runtime::namespace::Namespace;
 use teo_runtime::utils::ContainsStr;
 use teo_runtime::error_ext;
-use crate::bson_ext::teon_value_to_bson;
+use crate::bson_ext::{bson_to_teon_value, decimal_to_bson, freeform_value_to_bson, stringify_object_ids, teon_value_to_bson};
+use crate::connector::{ConnectionOptions, SubMillisecondPrecision, UnknownEnumMember};
 
 pub(crate) struct BsonCoder { }
 
 impl BsonCoder {
 
-    pub(crate) fn encode_without_default_type(value: &Value) -> Bson {
+    pub(crate) fn encode_without_default_type(value: &Value) -> Result<Bson> {
         teon_value_to_bson(value)
     }
 
-    pub(crate) fn encode<'a>(r#type: &Type, value: Value) -> Result<Bson> {
-        match r#type {
+    pub(crate) fn encode<'a>(options: &ConnectionOptions, r#type: &Type, value: Value) -> Result<Bson> {
+        match r#type.unwrap_optional() {
             Type::Int => if let Some(i) = value.as_int() {
                 Ok(Bson::Int32(i))
             } else {
@@ -31,44 +40,199 @@ impl BsonCoder {
             } else {
                 Ok(Bson::Null)
             },
-            _ => Ok(teon_value_to_bson(&value)),
+            Type::DateTime => if let Some(datetime) = value.as_datetime() {
+                if options.sub_millisecond_precision == SubMillisecondPrecision::Reject && datetime.timestamp_subsec_nanos() % 1_000_000 != 0 {
+                    Err(Error::new(format!("datetime {} has sub-millisecond precision which MongoDB can't store", datetime)))
+                } else {
+                    teon_value_to_bson(&value)
+                }
+            } else {
+                Ok(Bson::Null)
+            },
+            Type::Decimal => if let Some(decimal) = value.as_decimal() {
+                match decimal_to_bson(decimal) {
+                    Some(bson) => Ok(bson),
+                    None => Err(Error::new(format!("decimal {} cannot be stored as Decimal128 without rounding", decimal))),
+                }
+            } else {
+                Ok(Bson::Null)
+            },
+            Type::Bytes => match value {
+                Value::Bytes(bytes) => Ok(Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes })),
+                _ => Ok(Bson::Null),
+            },
+            Type::EnumVariant(reference) => match options.integer_enums.get(&reference.string_path().join(".")) {
+                Some(members) => match value.as_str() {
+                    Some(name) => Self::encode_integer_enum_member(members, &reference.string_path().join("."), name),
+                    None => Ok(Bson::Null),
+                },
+                None => teon_value_to_bson(&value),
+            },
+            // elements are encoded by their own type
+            Type::Array(inner) => match value {
+                Value::Array(values) => Ok(Bson::Array(values.into_iter().map(|v| Self::encode(options, inner, v)).collect::<Result<Vec<Bson>>>()?)),
+                _ => teon_value_to_bson(&value),
+            },
+            Type::Dictionary(inner) => match value {
+                Value::Dictionary(values) => Ok(Bson::Document(values.into_iter().map(|(k, v)| Ok((k, Self::encode(options, inner, v)?))).collect::<Result<Document>>()?)),
+                _ => teon_value_to_bson(&value),
+            },
+            Type::Any => freeform_value_to_bson(&value),
+            _ => teon_value_to_bson(&value),
         }
     }
 
-    pub(crate) fn decode<'a>(namespace: &Namespace, model: &Model, r#type: &Type, optional: bool, bson_value: &Bson, path: impl AsRef<KeyPath>) -> teo_result::Result<Value> {
-        if bson_value.as_null().is_some() && optional {
-            return Ok(Value::Null);
+    /// `encode`, failing instead of storing `null` when `value` of `name`
+    /// isn't of `r#type`, e.g. an array given for a single element.
+    pub(crate) fn encode_checked(options: &ConnectionOptions, r#type: &Type, value: Value, name: &str) -> Result<Bson> {
+        let is_null = matches!(value, Value::Null);
+        let bson = Self::encode(options, r#type, value)?;
+        if bson == Bson::Null && !is_null {
+            Err(Error::new(format!("value of `{}` doesn't match its type", name)))
+        } else {
+            Ok(bson)
         }
+    }
+
+    /// Encode a value compared against a field of `r#type` in a filter.
+    /// Arrays are encoded element by element.
+    pub(crate) fn encode_filter_value(options: &ConnectionOptions, r#type: &Type, value: &Value) -> Result<Bson> {
+        match (r#type.unwrap_optional(), value) {
+            (Type::EnumVariant(_), Value::String(_)) => Self::encode(options, r#type, value.clone()),
+            // object ids given as hex, e.g. from a url
+            (Type::ObjectId, Value::String(hex)) => match ObjectId::parse_str(hex) {
+                Ok(oid) => Ok(Bson::ObjectId(oid)),
+                Err(_) => Err(Error::new(format!("`{}` is not a valid object id", hex))),
+            },
+            (Type::Array(inner), Value::String(_)) => Self::encode_filter_value(options, inner, value),
+            (Type::Array(inner), Value::Array(values)) => Ok(Bson::Array(values.iter().map(|v| Self::encode_filter_value(options, inner, v)).collect::<Result<Vec<Bson>>>()?)),
+            // range bounds, e.g. `{ gt: { "$minKey": 1 } }`
+            (Type::Any, _) => freeform_value_to_bson(value),
+            (_, Value::Array(values)) => Ok(Bson::Array(values.iter().map(|v| Self::encode_filter_value(options, r#type, v)).collect::<Result<Vec<Bson>>>()?)),
+            _ => teon_value_to_bson(value),
+        }
+    }
+
+    fn number_as_f64(bson_value: &Bson) -> Option<f64> {
+        match bson_value {
+            Bson::Double(n) => Some(*n),
+            Bson::Int32(n) => Some(*n as f64),
+            Bson::Int64(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+
+    /// Numbers decode to the variant of the declared numeric type whatever
+    /// width they're stored with, as long as the value fits.
+    fn decode_number(r#type: &Type, bson_value: &Bson) -> Option<Value> {
+        match (r#type, bson_value) {
+            (Type::Int, Bson::Int32(n)) => Some(Value::Int(*n)),
+            (Type::Int, Bson::Int64(n)) => i32::try_from(*n).ok().map(Value::Int),
+            (Type::Int64, Bson::Int32(n)) => Some(Value::Int64(*n as i64)),
+            (Type::Int64, Bson::Int64(n)) => Some(Value::Int64(*n)),
+            (Type::Float32, _) => Self::number_as_f64(bson_value).map(|n| Value::Float32(n as f32)),
+            (Type::Float, _) => Self::number_as_f64(bson_value).map(Value::Float),
+            _ => None,
+        }
+    }
+
+    /// The integer member `name` of the enum at `enum_path` is stored as.
+    fn encode_integer_enum_member(members: &IndexMap<String, i32>, enum_path: &str, name: &str) -> Result<Bson> {
+        match members.get(name) {
+            Some(i) => Ok(Bson::Int32(*i)),
+            None => Err(Error::new(format!("`{}` has no integer value in enum `{}`", name, enum_path))),
+        }
+    }
+
+    /// The name of the member stored as the integer `bson_value`, or the
+    /// integer matching no member. `None` when `bson_value` isn't an integer.
+    fn decode_integer_enum_member<'a>(members: &'a IndexMap<String, i32>, bson_value: &Bson) -> Option<std::result::Result<&'a str, i64>> {
+        let stored = match bson_value {
+            Bson::Int32(i) => *i as i64,
+            Bson::Int64(i) => *i,
+            _ => return None,
+        };
+        Some(members.iter().find(|(_, i)| **i as i64 == stored).map(|(name, _)| name.as_str()).ok_or(stored))
+    }
+
+    /// Decode `stored`, which is no member of the enum at `enum_path`, as
+    /// `unknown_enum_member` tells.
+    fn decode_unknown_enum_member(options: &ConnectionOptions, model: &Model, enum_path: &str, stored: impl Display, optional: bool, path: &KeyPath) -> Result<Value> {
+        match Self::unknown_enum_member_value(options, &model.path().join("."), model.name(), enum_path, stored, optional) {
+            Some(value) => Ok(value),
+            None => Err(error_ext::record_decoding_error(model.name(), path, enum_path)),
+        }
+    }
+
+    /// The value `stored` decodes as under `UnknownEnumMember::Lenient`.
+    /// `None` when it can't be decoded.
+    fn unknown_enum_member_value(options: &ConnectionOptions, model_path: &str, model_name: &str, enum_path: &str, stored: impl Display, optional: bool) -> Option<Value> {
+        let UnknownEnumMember::Lenient(fallbacks) = &options.unknown_enum_member else {
+            return None;
+        };
+        let value = match fallbacks.get(enum_path) {
+            Some(fallback) => Value::String(fallback.clone()),
+            None if optional => Value::Null,
+            None => return None,
+        };
+        // once per model and enum, not for every record or stale member, so
+        // the issued keys stay bounded by the schema
+        options.warn_once(format!("{}.{}", model_path, enum_path), &format!("warning: {} records store values which are no member of enum {}, e.g. `{}`", model_name, enum_path, stored));
+        Some(value)
+    }
+
+    pub(crate) fn decode<'a>(options: &ConnectionOptions, namespace: &Namespace, model: &Model, r#type: &Type, optional: bool, bson_value: &Bson, path: impl AsRef<KeyPath>) -> teo_result::Result<Value> {
         let path = path.as_ref();
+        if bson_value.as_null().is_some() {
+            if optional || r#type.is_optional() || matches!(r#type, Type::Any) {
+                return Ok(Value::Null);
+            } else {
+                // e.g. a null element inside an array of non-nullable elements
+                return Err(error_ext::record_decoding_error(model.name(), path, "non-null value"));
+            }
+        }
         match r#type.unwrap_optional() {
-            Type::ObjectId => match bson_value.as_object_id() {
-                Some(oid) => Ok(Value::ObjectId(oid)),
-                None => Err(error_ext::record_decoding_error(model.name(), path, "object id")),
+            Type::Any => match bson_to_teon_value(bson_value) {
+                Some(value) if options.stringify_object_ids => Ok(stringify_object_ids(value)),
+                Some(value) => Ok(value),
+                None => Err(error_ext::record_decoding_error(model.name(), path, "any")),
+            }
+            Type::ObjectId => match bson_value {
+                Bson::ObjectId(oid) => Ok(Value::ObjectId(*oid)),
+                Bson::String(hex) if options.tolerant_object_ids => match ObjectId::parse_str(hex) {
+                    Ok(oid) => Ok(Value::ObjectId(oid)),
+                    Err(_) => Err(error_ext::record_decoding_error(model.name(), path, "object id")),
+                },
+                _ => Err(error_ext::record_decoding_error(model.name(), path, "object id")),
             }
             Type::Bool => match bson_value.as_bool() {
                 Some(b) => Ok(Value::Bool(b)),
                 None => Err(error_ext::record_decoding_error(model.name(), path, "bool")),
             }
-            Type::Int => match bson_value.as_i32() {
-                Some(n) => Ok(Value::Int(n)),
-                None => Err(error_ext::record_decoding_error(model.name(), path, "int 32")),
-            }
-            Type::Int64 => match bson_value.as_i64() {
-                Some(n) => Ok(Value::Int64(n)),
-                None => Err(error_ext::record_decoding_error(model.name(), path, "int 64")),
-            }
-            Type::Float32 => match bson_value.as_f64() {
-                Some(n) => Ok(Value::Float32(n as f32)),
-                None => Err(error_ext::record_decoding_error(model.name(), path, "double")),
+            Type::Int => Self::decode_number(r#type.unwrap_optional(), bson_value).ok_or_else(|| error_ext::record_decoding_error(model.name(), path, "int 32")),
+            Type::Int64 => Self::decode_number(r#type.unwrap_optional(), bson_value).ok_or_else(|| error_ext::record_decoding_error(model.name(), path, "int 64")),
+            Type::Float32 | Type::Float => Self::decode_number(r#type.unwrap_optional(), bson_value).ok_or_else(|| error_ext::record_decoding_error(model.name(), path, "double")),
+            Type::Decimal => match bson_value {
+                // `NaN`, infinities and other unparsable values fail to decode
+                Bson::Decimal128(d) => match BigDecimal::from_str(&d.to_string()) {
+                    Ok(d) => Ok(Value::Decimal(d)),
+                    Err(_) => Err(error_ext::record_decoding_error(model.name(), path, "decimal")),
+                },
+                // decimals written as numbers by other clients
+                Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) => match BigDecimal::from_str(&bson_value.to_string()) {
+                    Ok(d) => Ok(Value::Decimal(d)),
+                    Err(_) => Err(error_ext::record_decoding_error(model.name(), path, "decimal")),
+                },
+                _ => Err(error_ext::record_decoding_error(model.name(), path, "decimal")),
             }
-            Type::Float => match bson_value.as_f64() {
-                Some(n) => Ok(Value::Float(n)),
-                None => Err(error_ext::record_decoding_error(model.name(), path, "double")),
+            Type::String => match bson_value {
+                Bson::String(s) => Ok(Value::String(s.to_owned())),
+                Bson::ObjectId(oid) if options.tolerant_object_ids => Ok(Value::String(oid.to_hex())),
+                _ => Err(error_ext::record_decoding_error(model.name(), path, "string")),
             }
-            Type::Decimal => panic!("Decimal is not implemented by MongoDB."),
-            Type::String => match bson_value.as_str() {
-                Some(s) => Ok(Value::String(s.to_owned())),
-                None => Err(error_ext::record_decoding_error(model.name(), path, "string")),
+            Type::Bytes => match bson_value {
+                Bson::Binary(binary) if binary.subtype == BinarySubtype::Generic => Ok(Value::Bytes(binary.bytes.clone())),
+                _ => Err(error_ext::record_decoding_error(model.name(), path, "generic binary")),
             }
             Type::Date => match bson_value.as_datetime() {
                 Some(val) => Ok(Value::Date(val.to_chrono().date_naive())),
@@ -78,36 +242,286 @@ impl BsonCoder {
                 Some(val) => Ok(Value::DateTime(val.to_chrono())),
                 None => Err(error_ext::record_decoding_error(model.name(), path, "datetime")),
             }
-            Type::EnumVariant(reference) => match bson_value.as_str() {
-                Some(val) => {
-                    let e = namespace.enum_at_path(reference.string_path()).unwrap();
-                    if e.member_names().contains_str(val) {
-                        Ok(Value::String(val.to_owned()))
-                    } else {
-                        Err(error_ext::record_decoding_error(model.name(), path, &e.path().join(".")))
-                    }
-                },
-                None => Err(error_ext::record_decoding_error(model.name(), path, "string")),
+            Type::EnumVariant(reference) => if let Some(members) = options.integer_enums.get(&reference.string_path().join(".")) {
+                match Self::decode_integer_enum_member(members, bson_value) {
+                    Some(Ok(name)) => Ok(Value::String(name.to_owned())),
+                    Some(Err(stored)) => Self::decode_unknown_enum_member(options, model, &reference.string_path().join("."), stored, optional || r#type.is_optional(), path),
+                    None => Err(error_ext::record_decoding_error(model.name(), path, "int")),
+                }
+            } else {
+                match bson_value.as_str() {
+                    Some(val) => {
+                        let e = namespace.enum_at_path(reference.string_path()).unwrap();
+                        if e.member_names().contains_str(val) {
+                            Ok(Value::String(val.to_owned()))
+                        } else {
+                            Self::decode_unknown_enum_member(options, model, &e.path().join("."), val, optional || r#type.is_optional(), path)
+                        }
+                    },
+                    None => Err(error_ext::record_decoding_error(model.name(), path, "string")),
+                }
             }
             Type::Array(inner_field) => {
                 match bson_value.as_array() {
                     Some(arr) => Ok(Value::Array(arr.iter().enumerate().map(|(i, v)| {
                         let path = path + i;
-                        Self::decode(namespace, model, inner_field.unwrap_optional(), inner_field.is_optional(), v, path)
+                        Self::decode(options, namespace, model, inner_field.unwrap_optional(), inner_field.is_optional(), v, path)
                     }).collect::<teo_result::Result<Vec<Value>>>()?)),
                     None => Err(error_ext::record_decoding_error(model.name(), path, "array")),
                 }
             }
             Type::Dictionary(inner_field) => {
+                // keys are kept in stored order
                 match bson_value.as_document() {
                     Some(doc) => Ok(Value::Dictionary(doc.iter().map(|(k, v)| {
                         let path = path + k;
-                        Ok((k.to_owned(), Self::decode(namespace, model, inner_field.unwrap_optional(), inner_field.is_optional(), v, path)?))
+                        Ok((k.to_owned(), Self::decode(options, namespace, model, inner_field.unwrap_optional(), inner_field.is_optional(), v, path)?))
                     }).collect::<Result<IndexMap<String, Value>>>()?)),
                     None => Err(error_ext::record_decoding_error(model.name(), path, "document")),
                 }
             }
+            Type::InterfaceObject(reference, generics) => {
+                // structured documents, e.g. elements of an array of line items
+                let interface = namespace.interface_at_path(&reference.string_path()).unwrap();
+                // fields of `Page<T>` typed `T` decode as the given argument
+                let arguments: HashMap<&str, &Type> = interface.generic_names().iter().map(String::as_str).zip(generics.iter()).collect();
+                match bson_value.as_document() {
+                    Some(doc) => {
+                        let mut retval: IndexMap<String, Value> = IndexMap::new();
+                        for (name, field) in interface.fields() {
+                            let path = path + name.as_str();
+                            match doc.get(name.as_str()) {
+                                Some(v) => {
+                                    let field_type = Self::resolve_generics(field.r#type(), &arguments);
+                                    retval.insert(name.to_owned(), Self::decode(options, namespace, model, &field_type, field.is_optional(), v, path)?);
+                                }
+                                None => if !field.is_optional() {
+                                    return Err(error_ext::record_decoding_error(model.name(), path, "value"));
+                                }
+                            }
+                        }
+                        Ok(Value::Dictionary(retval))
+                    }
+                    None => Err(error_ext::record_decoding_error(model.name(), path, "document")),
+                }
+            }
             _ => unreachable!()
         }
     }
+
+    /// `r#type` with the generic parameters of an interface replaced by their
+    /// `arguments`.
+    fn resolve_generics(r#type: &Type, arguments: &HashMap<&str, &Type>) -> Type {
+        match r#type {
+            Type::GenericItem(name) => arguments.get(name.as_str()).map_or_else(|| r#type.clone(), |argument| (*argument).clone()),
+            Type::Optional(inner) => Type::Optional(Box::new(Self::resolve_generics(inner, arguments))),
+            Type::Array(inner) => Type::Array(Box::new(Self::resolve_generics(inner, arguments))),
+            Type::Dictionary(inner) => Type::Dictionary(Box::new(Self::resolve_generics(inner, arguments))),
+            Type::InterfaceObject(reference, generics) => Type::InterfaceObject(reference.clone(), generics.iter().map(|generic| Self::resolve_generics(generic, arguments)).collect()),
+            _ => r#type.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use bigdecimal::BigDecimal;
+    use bson::{Binary, Bson};
+    use bson::spec::BinarySubtype;
+    use teo_parser::r#type::Type;
+    use teo_runtime::Value;
+    use chrono::{TimeZone, Utc};
+    use indexmap::IndexMap;
+    use crate::connector::{ConnectionOptions, SubMillisecondPrecision, UnknownEnumMember, WarningHandler};
+    use super::BsonCoder;
+
+    #[test]
+    fn object_ids_are_matched_from_hex() {
+        let options = ConnectionOptions::default();
+        let oid = bson::oid::ObjectId::new();
+        let hex = Value::String(oid.to_hex());
+        assert_eq!(BsonCoder::encode_filter_value(&options, &Type::ObjectId, &hex).unwrap(), Bson::ObjectId(oid));
+        assert_eq!(BsonCoder::encode_filter_value(&options, &Type::Optional(Box::new(Type::ObjectId)), &Value::Array(vec![hex])).unwrap(), Bson::Array(vec![Bson::ObjectId(oid)]));
+        assert!(BsonCoder::encode_filter_value(&options, &Type::ObjectId, &Value::String("not-an-id".to_owned())).is_err());
+    }
+
+    #[test]
+    fn only_freeform_fields_encode_special_dictionaries() {
+        let options = ConnectionOptions::default();
+        let max_key = Value::Dictionary(IndexMap::from([("$maxKey".to_owned(), Value::Int(1))]));
+        assert_eq!(BsonCoder::encode(&options, &Type::Any, max_key.clone()).unwrap(), Bson::MaxKey);
+        assert_eq!(BsonCoder::encode_filter_value(&options, &Type::Any, &max_key).unwrap(), Bson::MaxKey);
+        let dictionary = Type::Dictionary(Box::new(Type::Int));
+        assert_eq!(BsonCoder::encode(&options, &dictionary, max_key).unwrap(), Bson::Document(bson::doc!{"$maxKey": 1}));
+    }
+
+    #[test]
+    fn decode_number_of_any_width() {
+        assert!(matches!(BsonCoder::decode_number(&Type::Int, &Bson::Int64(42)), Some(Value::Int(42))));
+        assert!(matches!(BsonCoder::decode_number(&Type::Int64, &Bson::Int32(42)), Some(Value::Int64(42))));
+        assert!(matches!(BsonCoder::decode_number(&Type::Float, &Bson::Int32(2)), Some(Value::Float(n)) if n == 2.0));
+        assert!(matches!(BsonCoder::decode_number(&Type::Float32, &Bson::Int64(2)), Some(Value::Float32(n)) if n == 2.0));
+    }
+
+    #[test]
+    fn decode_number_to_the_runtime_variants() {
+        assert!(matches!(BsonCoder::decode_number(&Type::Int, &Bson::Int32(42)), Some(Value::Int(42))));
+        assert!(matches!(BsonCoder::decode_number(&Type::Int64, &Bson::Int64(42)), Some(Value::Int64(42))));
+        assert!(matches!(BsonCoder::decode_number(&Type::Float32, &Bson::Double(0.5)), Some(Value::Float32(n)) if n == 0.5));
+        assert!(matches!(BsonCoder::decode_number(&Type::Float, &Bson::Double(0.5)), Some(Value::Float(n)) if n == 0.5));
+    }
+
+    #[test]
+    fn decode_number_which_doesnt_fit() {
+        assert!(BsonCoder::decode_number(&Type::Int, &Bson::Int64(i64::MAX)).is_none());
+        assert!(BsonCoder::decode_number(&Type::Int, &Bson::Double(1.5)).is_none());
+        assert!(BsonCoder::decode_number(&Type::Int64, &Bson::String("1".to_owned())).is_none());
+    }
+
+    #[test]
+    fn generic_parameters_are_resolved() {
+        let arguments = HashMap::from([("T", &Type::Int)]);
+        let resolved = BsonCoder::resolve_generics(&Type::Optional(Box::new(Type::Array(Box::new(Type::GenericItem("T".to_owned()))))), &arguments);
+        let Type::Optional(array) = resolved else {
+            panic!("expected an optional type");
+        };
+        let Type::Array(element) = *array else {
+            panic!("expected an array type");
+        };
+        assert!(matches!(*element, Type::Int));
+    }
+
+    #[test]
+    fn unknown_generic_parameters_are_kept() {
+        let arguments = HashMap::new();
+        assert!(matches!(BsonCoder::resolve_generics(&Type::GenericItem("T".to_owned()), &arguments), Type::GenericItem(name) if name == "T"));
+    }
+
+    #[test]
+    fn encode_decimal() {
+        let options = ConnectionOptions::default();
+        let decimal = BigDecimal::from_str("0.1").unwrap();
+        let encoded = BsonCoder::encode(&options, &Type::Decimal, Value::Decimal(decimal.clone())).unwrap();
+        let Bson::Decimal128(stored) = encoded else {
+            panic!("expected a Decimal128");
+        };
+        assert_eq!(BigDecimal::from_str(&stored.to_string()).unwrap(), decimal);
+    }
+
+    #[test]
+    fn encode_decimal_which_needs_rounding() {
+        let options = ConnectionOptions::default();
+        let decimal = BigDecimal::from_str("1.234567890123456789012345678901234567891").unwrap();
+        assert!(BsonCoder::encode(&options, &Type::Decimal, Value::Decimal(decimal)).is_err());
+    }
+
+    #[test]
+    fn encode_array_elements_by_their_type() {
+        let options = ConnectionOptions::default();
+        let encoded = BsonCoder::encode(&options, &Type::Array(Box::new(Type::Int64)), Value::Array(vec![Value::Int64(1), Value::Null])).unwrap();
+        assert_eq!(encoded, Bson::Array(vec![Bson::Int64(1), Bson::Null]));
+    }
+
+    #[test]
+    fn encode_array_element_errors() {
+        let decimal = BigDecimal::from_str("1.234567890123456789012345678901234567891").unwrap();
+        let options = ConnectionOptions::default();
+        assert!(BsonCoder::encode(&options, &Type::Array(Box::new(Type::Decimal)), Value::Array(vec![Value::Decimal(decimal)])).is_err());
+        let options = ConnectionOptions { sub_millisecond_precision: SubMillisecondPrecision::Reject, ..Default::default() };
+        let datetime = Utc.timestamp_opt(0, 1_500).unwrap();
+        assert!(BsonCoder::encode(&options, &Type::Array(Box::new(Type::DateTime)), Value::Array(vec![Value::DateTime(datetime)])).is_err());
+    }
+
+    #[test]
+    fn encode_dictionary_values_by_their_type() {
+        let options = ConnectionOptions::default();
+        let decimal = BigDecimal::from_str("1.5").unwrap();
+        let value = Value::Dictionary(IndexMap::from([("price".to_owned(), Value::Decimal(decimal.clone()))]));
+        let encoded = BsonCoder::encode(&options, &Type::Dictionary(Box::new(Type::Decimal)), value).unwrap();
+        let Some(Bson::Decimal128(price)) = encoded.as_document().and_then(|document| document.get("price")) else {
+            panic!("expected a Decimal128");
+        };
+        assert_eq!(BigDecimal::from_str(&price.to_string()).unwrap(), decimal);
+    }
+
+    #[test]
+    fn encode_bytes_as_generic_binary() {
+        let options = ConnectionOptions::default();
+        let encoded = BsonCoder::encode(&options, &Type::Bytes, Value::Bytes(vec![0, 1, 255])).unwrap();
+        assert_eq!(encoded, Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes: vec![0, 1, 255] }));
+    }
+
+    #[test]
+    fn encode_checked_rejects_mismatched_values() {
+        let options = ConnectionOptions::default();
+        assert_eq!(BsonCoder::encode_checked(&options, &Type::Int, Value::Int(1), "count").unwrap(), Bson::Int32(1));
+        assert_eq!(BsonCoder::encode_checked(&options, &Type::Optional(Box::new(Type::Int)), Value::Null, "count").unwrap(), Bson::Null);
+        assert!(BsonCoder::encode_checked(&options, &Type::Int, Value::Array(vec![Value::Int(1)]), "count").is_err());
+        assert!(BsonCoder::encode_checked(&options, &Type::Int, Value::String("1".to_owned()), "count").is_err());
+    }
+
+    fn recording_options(unknown_enum_member: UnknownEnumMember) -> (ConnectionOptions, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let sink = received.clone();
+        let options = ConnectionOptions {
+            unknown_enum_member,
+            warning_handler: WarningHandler::new(move |message| sink.lock().unwrap().push(message.to_owned())),
+            ..Default::default()
+        };
+        (options, received)
+    }
+
+    #[test]
+    fn unknown_enum_members_fail_by_default() {
+        let (options, received) = recording_options(UnknownEnumMember::Reject);
+        assert!(BsonCoder::unknown_enum_member_value(&options, "Task", "Task", "Status", "archived", true).is_none());
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_enum_members_fall_back_when_lenient() {
+        let (options, received) = recording_options(UnknownEnumMember::Lenient(HashMap::from([("Status".to_owned(), "open".to_owned())])));
+        let value = BsonCoder::unknown_enum_member_value(&options, "Task", "Task", "Status", "archived", false);
+        assert!(matches!(value, Some(Value::String(member)) if member == "open"));
+        assert!(matches!(BsonCoder::unknown_enum_member_value(&options, "Task", "Task", "Priority", "urgent", true), Some(Value::Null)));
+        assert!(BsonCoder::unknown_enum_member_value(&options, "Task", "Task", "Priority", "urgent", false).is_none());
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unknown_enum_member_warnings_are_issued_once_per_enum() {
+        let (options, received) = recording_options(UnknownEnumMember::Lenient(HashMap::from([("Status".to_owned(), "open".to_owned())])));
+        for stored in ["archived", "deleted", "archived"] {
+            BsonCoder::unknown_enum_member_value(&options, "Task", "Task", "Status", stored, false);
+        }
+        BsonCoder::unknown_enum_member_value(&options, "Project", "Project", "Status", "archived", false);
+        assert_eq!(*received.lock().unwrap(), vec![
+            "warning: Task records store values which are no member of enum Status, e.g. `archived`".to_owned(),
+            "warning: Project records store values which are no member of enum Status, e.g. `archived`".to_owned(),
+        ]);
+    }
+
+    fn priorities() -> IndexMap<String, i32> {
+        IndexMap::from([("low".to_owned(), 0), ("high".to_owned(), 10)])
+    }
+
+    #[test]
+    fn integer_enum_members_round_trip() {
+        let members = priorities();
+        let stored = BsonCoder::encode_integer_enum_member(&members, "Priority", "high").unwrap();
+        assert_eq!(stored, Bson::Int32(10));
+        assert_eq!(BsonCoder::decode_integer_enum_member(&members, &stored), Some(Ok("high")));
+        assert_eq!(BsonCoder::decode_integer_enum_member(&members, &Bson::Int64(0)), Some(Ok("low")));
+    }
+
+    #[test]
+    fn unknown_integer_enum_members_are_rejected() {
+        let members = priorities();
+        assert!(BsonCoder::encode_integer_enum_member(&members, "Priority", "urgent").is_err());
+        assert_eq!(BsonCoder::decode_integer_enum_member(&members, &Bson::Int32(5)), Some(Err(5)));
+        assert_eq!(BsonCoder::decode_integer_enum_member(&members, &Bson::String("high".to_owned())), None);
+    }
 }
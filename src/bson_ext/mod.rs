@@ -1,12 +1,27 @@
-use bson::Bson;
+use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use bson::{Binary, Bson, Decimal128, Document, Timestamp};
+use bson::spec::BinarySubtype;
 use bson::datetime::{DateTime as BsonDateTime};
 use chrono::{NaiveDateTime, NaiveTime, TimeZone, Utc};
+use indexmap::IndexMap;
+use teo_result::{Error, Result};
 use teo_runtime::Value;
 
 pub(crate) mod coder;
 
-pub(crate) fn teon_value_to_bson(value: &Value) -> Bson {
-    match value {
+/// Freeform representation of `MinKey`, decoded as `{ "$minKey": 1 }`.
+pub(crate) const MIN_KEY: &str = "$minKey";
+/// Freeform representation of `MaxKey`, decoded as `{ "$maxKey": 1 }`.
+pub(crate) const MAX_KEY: &str = "$maxKey";
+/// Freeform representation of `Timestamp`, decoded as
+/// `{ "$timestamp": { "seconds": s, "increment": i } }`.
+pub(crate) const TIMESTAMP: &str = "$timestamp";
+
+/// Encode a value without a declared type. Fails for decimals which can't
+/// be stored as `Decimal128` and values which have no bson representation.
+pub(crate) fn teon_value_to_bson(value: &Value) -> Result<Bson> {
+    Ok(match value {
         Value::Null => Bson::Null,
         Value::ObjectId(oid) => Bson::ObjectId(oid.clone()),
         Value::Bool(b) => Bson::Boolean(*b),
@@ -14,12 +29,314 @@ pub(crate) fn teon_value_to_bson(value: &Value) -> Bson {
         Value::Int64(i) => Bson::Int64(*i),
         Value::Float32(f) => Bson::Double(*f as f64),
         Value::Float(f) => Bson::Double(*f),
-        Value::Decimal(_d) => panic!("Decimal is not implemented by MongoDB."),
+        Value::Decimal(d) => match decimal_to_bson(d) {
+            Some(bson) => bson,
+            None => return Err(Error::new(format!("decimal {} cannot be stored as Decimal128 without rounding", d))),
+        },
         Value::String(s) => Bson::String(s.clone()),
+        Value::Bytes(bytes) => Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes: bytes.clone() }),
         Value::Date(val) => Bson::DateTime(BsonDateTime::from(Utc.from_utc_datetime(&NaiveDateTime::new(val.clone(), NaiveTime::default())))),
         Value::DateTime(val) => Bson::DateTime(BsonDateTime::from(*val)),
-        Value::Array(val) => Bson::Array(val.iter().map(|i| { teon_value_to_bson(i) }).collect()),
-        Value::Dictionary(val) => Bson::Document(val.iter().map(|(k, v)| (k.clone(), teon_value_to_bson(v))).collect()),
-        _ => panic!("Cannot convert to Bson value.")
+        Value::Array(val) => Bson::Array(val.iter().map(teon_value_to_bson).collect::<Result<Vec<Bson>>>()?),
+        Value::Dictionary(val) => Bson::Document(val.iter().map(|(k, v)| Ok((k.clone(), teon_value_to_bson(v)?))).collect::<Result<Document>>()?),
+        _ => return Err(Error::new("value cannot be stored in MongoDB")),
+    })
+}
+
+/// Encode the value of a freeform field, where the representations of
+/// `MinKey`, `MaxKey` and `Timestamp` decoded from it are encoded back.
+/// Other values, e.g. pipeline stages, keep such dictionaries as they are.
+pub(crate) fn freeform_value_to_bson(value: &Value) -> Result<Bson> {
+    Ok(match value {
+        Value::Array(val) => Bson::Array(val.iter().map(freeform_value_to_bson).collect::<Result<Vec<Bson>>>()?),
+        Value::Dictionary(val) => match special_dictionary_to_bson(val)? {
+            Some(special) => special,
+            None => Bson::Document(val.iter().map(|(k, v)| Ok((k.clone(), freeform_value_to_bson(v)?))).collect::<Result<Document>>()?),
+        },
+        _ => teon_value_to_bson(value)?,
+    })
+}
+
+/// Encode a decimal as `Decimal128`, so it's compared exactly instead of as
+/// a double. `None` when it can't be stored without rounding.
+pub(crate) fn decimal_to_bson(decimal: &BigDecimal) -> Option<Bson> {
+    Decimal128::from_str(&decimal.to_string()).ok().map(Bson::Decimal128)
+}
+
+/// The bson value of a special dictionary, failing for ones which are
+/// malformed instead of storing them as documents.
+fn special_dictionary_to_bson(dictionary: &IndexMap<String, Value>) -> Result<Option<Bson>> {
+    if dictionary.len() != 1 {
+        return Ok(None);
+    }
+    let (key, value) = dictionary.iter().next().unwrap();
+    match key.as_str() {
+        MIN_KEY | MAX_KEY => match value.to_int64() {
+            Some(1) => Ok(Some(if key == MIN_KEY { Bson::MinKey } else { Bson::MaxKey })),
+            _ => Err(Error::new(format!("`{}` takes 1", key))),
+        },
+        TIMESTAMP => {
+            let Some(fields) = value.as_dictionary().filter(|fields| fields.len() == 2) else {
+                return Err(Error::new("`$timestamp` takes `seconds` and `increment`"));
+            };
+            Ok(Some(Bson::Timestamp(Timestamp {
+                time: timestamp_part(fields, "seconds")?,
+                increment: timestamp_part(fields, "increment")?,
+            })))
+        },
+        _ => Ok(None),
+    }
+}
+
+fn timestamp_part(fields: &IndexMap<String, Value>, name: &str) -> Result<u32> {
+    match fields.get(name).and_then(|value| value.to_int64()).map(u32::try_from) {
+        Some(Ok(part)) => Ok(part),
+        Some(Err(_)) => Err(Error::new(format!("`{}` of `$timestamp` is out of range", name))),
+        None => Err(Error::new("`$timestamp` takes `seconds` and `increment`")),
+    }
+}
+
+/// Decode a bson value without a declared type. Returns `None` for bson
+/// types which have no teon representation.
+pub(crate) fn bson_to_teon_value(bson: &Bson) -> Option<Value> {
+    Some(match bson {
+        Bson::Null | Bson::Undefined => Value::Null,
+        Bson::ObjectId(oid) => Value::ObjectId(oid.clone()),
+        Bson::Boolean(b) => Value::Bool(*b),
+        Bson::Int32(i) => Value::Int(*i),
+        Bson::Int64(i) => Value::Int64(*i),
+        Bson::Double(f) => Value::Float(*f),
+        Bson::Decimal128(d) => Value::Decimal(BigDecimal::from_str(&d.to_string()).ok()?),
+        Bson::String(s) => Value::String(s.clone()),
+        Bson::Binary(binary) if binary.subtype == BinarySubtype::Generic => Value::Bytes(binary.bytes.clone()),
+        Bson::DateTime(val) => Value::DateTime(val.to_chrono()),
+        Bson::Array(val) => Value::Array(val.iter().map(|i| bson_to_teon_value(i)).collect::<Option<Vec<Value>>>()?),
+        Bson::Document(val) => Value::Dictionary(val.iter().map(|(k, v)| Some((k.clone(), bson_to_teon_value(v)?))).collect::<Option<IndexMap<String, Value>>>()?),
+        Bson::MinKey => Value::Dictionary(IndexMap::from([(MIN_KEY.to_owned(), Value::Int(1))])),
+        Bson::MaxKey => Value::Dictionary(IndexMap::from([(MAX_KEY.to_owned(), Value::Int(1))])),
+        Bson::Timestamp(timestamp) => Value::Dictionary(IndexMap::from([(TIMESTAMP.to_owned(), Value::Dictionary(IndexMap::from([
+            ("seconds".to_owned(), Value::Int64(timestamp.time as i64)),
+            ("increment".to_owned(), Value::Int64(timestamp.increment as i64)),
+        ])))])),
+        _ => return None,
+    })
+}
+
+/// Encode the value of a raw field, given in canonical extended JSON, e.g.
+/// `{ "$numberLong": "1" }`. Plain numbers keep their variant, and
+/// `Float32`, which bson has no type for, is rejected.
+pub(crate) fn raw_value_to_bson(value: &Value) -> Result<Bson> {
+    match Bson::try_from(raw_value_to_json(value)?) {
+        Ok(bson) => Ok(bson),
+        Err(err) => Err(Error::new(format!("invalid extended JSON: {}", err))),
+    }
+}
+
+fn raw_value_to_json(value: &Value) -> Result<serde_json::Value> {
+    Ok(match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Bool(b) => serde_json::Value::Bool(*b),
+        Value::String(s) => serde_json::Value::String(s.clone()),
+        Value::Float32(_) => return Err(Error::new("32-bit floats cannot be stored verbatim")),
+        Value::Array(values) => serde_json::Value::Array(values.iter().map(raw_value_to_json).collect::<Result<Vec<serde_json::Value>>>()?),
+        Value::Dictionary(values) => serde_json::Value::Object(values.iter().map(|(k, v)| Ok((k.clone(), raw_value_to_json(v)?))).collect::<Result<serde_json::Map<String, serde_json::Value>>>()?),
+        _ => teon_value_to_bson(value)?.into_canonical_extjson(),
+    })
+}
+
+/// Decode the value of a raw field as canonical extended JSON, which keeps
+/// every bson type, e.g. `Int64` as `{ "$numberLong": "1" }`.
+pub(crate) fn raw_bson_to_value(bson: &Bson) -> Value {
+    json_to_teon_value(bson.clone().into_canonical_extjson())
+}
+
+fn json_to_teon_value(json: serde_json::Value) -> Value {
+    match json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Bool(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Int64(i),
+            None => Value::Float(n.as_f64().unwrap()),
+        },
+        serde_json::Value::String(s) => Value::String(s),
+        serde_json::Value::Array(values) => Value::Array(values.into_iter().map(json_to_teon_value).collect()),
+        serde_json::Value::Object(values) => Value::Dictionary(values.into_iter().map(|(k, v)| (k, json_to_teon_value(v))).collect()),
+    }
+}
+
+/// Replace object ids nested anywhere in `value` with their hex strings.
+pub(crate) fn stringify_object_ids(value: Value) -> Value {
+    match value {
+        Value::ObjectId(oid) => Value::String(oid.to_hex()),
+        Value::Array(values) => Value::Array(values.into_iter().map(stringify_object_ids).collect()),
+        Value::Dictionary(values) => Value::Dictionary(values.into_iter().map(|(k, v)| (k, stringify_object_ids(v))).collect()),
+        value => value,
+    }
+}
+
+/// Decode a document leniently, dropping values which have no teon
+/// representation instead of failing. Keys keep their stored order.
+pub(crate) fn document_to_teon_value(document: &Document) -> Value {
+    Value::Dictionary(document.iter().filter_map(|(k, v)| Some((k.clone(), bson_to_teon_value(v)?))).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use bson::{doc, Binary, Bson, Timestamp};
+    use bson::oid::ObjectId;
+    use bson::spec::BinarySubtype;
+    use indexmap::IndexMap;
+    use teo_runtime::Value;
+    use super::{bson_to_teon_value, decimal_to_bson, document_to_teon_value, freeform_value_to_bson, raw_bson_to_value, raw_value_to_bson, stringify_object_ids, teon_value_to_bson};
+
+    fn round_trip(bson: Bson) -> Bson {
+        freeform_value_to_bson(&bson_to_teon_value(&bson).unwrap()).unwrap()
+    }
+
+    fn dictionary(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dictionary(entries.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+
+    fn timestamp(seconds: Value, increment: Value) -> Value {
+        dictionary(vec![("$timestamp", dictionary(vec![("seconds", seconds), ("increment", increment)]))])
+    }
+
+    #[test]
+    fn min_key_and_max_key_round_trip() {
+        assert_eq!(round_trip(Bson::MinKey), Bson::MinKey);
+        assert_eq!(round_trip(Bson::MaxKey), Bson::MaxKey);
+    }
+
+    #[test]
+    fn timestamp_round_trips() {
+        let timestamp = Bson::Timestamp(Timestamp { time: 1_700_000_000, increment: 7 });
+        assert_eq!(round_trip(timestamp.clone()), timestamp);
+    }
+
+    #[test]
+    fn special_dictionaries_are_only_converted_in_freeform_values() {
+        let min_key = dictionary(vec![("$minKey", Value::Int(1))]);
+        assert_eq!(teon_value_to_bson(&min_key).unwrap(), Bson::Document(doc!{"$minKey": 1}));
+        let nested = dictionary(vec![("bounds", Value::Array(vec![min_key]))]);
+        assert_eq!(freeform_value_to_bson(&nested).unwrap(), Bson::Document(doc!{"bounds": [Bson::MinKey]}));
+    }
+
+    #[test]
+    fn malformed_special_dictionaries_are_rejected() {
+        assert!(freeform_value_to_bson(&dictionary(vec![("$maxKey", Value::Int(2))])).is_err());
+        assert!(freeform_value_to_bson(&dictionary(vec![("$minKey", Value::String("1".to_owned()))])).is_err());
+        assert!(freeform_value_to_bson(&dictionary(vec![("$timestamp", Value::Int(1))])).is_err());
+        assert!(freeform_value_to_bson(&dictionary(vec![("$timestamp", dictionary(vec![("seconds", Value::Int(1))]))])).is_err());
+    }
+
+    #[test]
+    fn timestamp_parts_out_of_range_are_rejected() {
+        let error = freeform_value_to_bson(&timestamp(Value::Int64(u32::MAX as i64 + 1), Value::Int(0))).unwrap_err();
+        assert!(error.to_string().contains("`seconds` of `$timestamp` is out of range"));
+        assert!(freeform_value_to_bson(&timestamp(Value::Int(1), Value::Int(-1))).is_err());
+        let largest = freeform_value_to_bson(&timestamp(Value::Int64(u32::MAX as i64), Value::Int64(u32::MAX as i64))).unwrap();
+        assert_eq!(largest, Bson::Timestamp(Timestamp { time: u32::MAX, increment: u32::MAX }));
+    }
+
+    #[test]
+    fn generic_binary_round_trips() {
+        let binary = Bson::Binary(Binary { subtype: BinarySubtype::Generic, bytes: vec![0, 1, 255] });
+        assert_eq!(round_trip(binary.clone()), binary);
+    }
+
+    #[test]
+    fn nested_object_ids_are_stringified() {
+        let oid = ObjectId::new();
+        let value = Value::Dictionary(IndexMap::from([
+            ("id".to_owned(), Value::ObjectId(oid)),
+            ("refs".to_owned(), Value::Array(vec![Value::ObjectId(oid), Value::Int(1)])),
+        ]));
+        let Value::Dictionary(stringified) = stringify_object_ids(value) else {
+            panic!("expected a dictionary");
+        };
+        assert!(matches!(stringified.get("id"), Some(Value::String(hex)) if *hex == oid.to_hex()));
+        let Some(Value::Array(refs)) = stringified.get("refs") else {
+            panic!("expected an array");
+        };
+        assert!(matches!(&refs[0], Value::String(hex) if *hex == oid.to_hex()));
+        assert!(matches!(&refs[1], Value::Int(1)));
+    }
+
+    #[test]
+    fn decimal_is_stored_as_decimal128() {
+        let decimal = BigDecimal::from_str("12.345").unwrap();
+        let Some(Bson::Decimal128(stored)) = decimal_to_bson(&decimal) else {
+            panic!("expected a Decimal128");
+        };
+        assert_eq!(BigDecimal::from_str(&stored.to_string()).unwrap(), decimal);
+    }
+
+    #[test]
+    fn decimal_which_needs_rounding_is_rejected() {
+        let decimal = BigDecimal::from_str("1.234567890123456789012345678901234567891").unwrap();
+        assert!(decimal_to_bson(&decimal).is_none());
+        assert!(teon_value_to_bson(&Value::Decimal(decimal)).is_err());
+    }
+
+    #[test]
+    fn nested_decimal_error_is_returned() {
+        let decimal = BigDecimal::from_str("1.234567890123456789012345678901234567891").unwrap();
+        assert!(teon_value_to_bson(&Value::Array(vec![Value::Int(1), Value::Decimal(decimal)])).is_err());
+    }
+
+    #[test]
+    fn freeform_documents_keep_their_key_order() {
+        let document = doc!{"zeta": 1, "alpha": {"mid": 2, "beta": 3}, "mid": 4};
+        let Value::Dictionary(decoded) = document_to_teon_value(&document) else {
+            panic!("expected a dictionary");
+        };
+        assert_eq!(decoded.keys().collect::<Vec<_>>(), vec!["zeta", "alpha", "mid"]);
+        let Some(Value::Dictionary(nested)) = decoded.get("alpha") else {
+            panic!("expected a dictionary");
+        };
+        assert_eq!(nested.keys().collect::<Vec<_>>(), vec!["mid", "beta"]);
+    }
+
+    #[test]
+    fn raw_values_keep_their_number_types() {
+        let decimal = bson::Decimal128::from_str("1.10").unwrap();
+        let stored = Bson::Document(doc!{"int32": 1_i32, "int64": 1_i64, "double": 1.0, "decimal": decimal});
+        let value = raw_bson_to_value(&stored);
+        assert_eq!(raw_value_to_bson(&value).unwrap(), stored);
+        let Value::Dictionary(fields) = value else {
+            panic!("expected a dictionary");
+        };
+        assert!(matches!(fields.get("int64"), Some(Value::Dictionary(wrapper)) if wrapper.contains_key("$numberLong")));
+    }
+
+    #[test]
+    fn raw_values_keep_types_without_a_teon_representation() {
+        let stored = Bson::Document(doc!{
+            "regex": Bson::RegularExpression(bson::Regex { pattern: "^a".to_owned(), options: "i".to_owned() }),
+            "uuid": Bson::Binary(Binary { subtype: BinarySubtype::Uuid, bytes: vec![0; 16] }),
+            "symbol": Bson::Symbol("s".to_owned()),
+            "code": Bson::JavaScriptCode("1".to_owned()),
+        });
+        assert_eq!(raw_value_to_bson(&raw_bson_to_value(&stored)).unwrap(), stored);
+    }
+
+    #[test]
+    fn raw_plain_values_keep_their_variant() {
+        let value = Value::Array(vec![Value::Int(1), Value::Int64(1), Value::Float(1.0)]);
+        assert_eq!(raw_value_to_bson(&value).unwrap(), Bson::Array(vec![Bson::Int32(1), Bson::Int64(1), Bson::Double(1.0)]));
+        assert!(raw_value_to_bson(&Value::Float32(1.0)).is_err());
+    }
+
+    #[test]
+    fn db_refs_decode_as_dictionaries() {
+        let oid = ObjectId::new();
+        let Value::Dictionary(db_ref) = document_to_teon_value(&doc!{"$ref": "users", "$id": oid, "$db": "shop"}) else {
+            panic!("expected a dictionary");
+        };
+        assert!(matches!(db_ref.get("$ref"), Some(Value::String(collection)) if collection == "users"));
+        assert!(matches!(db_ref.get("$id"), Some(Value::ObjectId(id)) if *id == oid));
+        assert!(matches!(db_ref.get("$db"), Some(Value::String(database)) if database == "shop"));
     }
 }
@@ -1,54 +1,199 @@
+use std::future::Future;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 use async_trait::async_trait;
 use bson::{doc, Document};
 use mongodb::{Client, Collection, Database};
-use mongodb::options::ClientOptions;
+use mongodb::options::{ClientOptions, ReadPreference, SelectionCriteria, TransactionOptions};
 use teo_runtime::connection::connection::Connection;
 use teo_runtime::connection::transaction::Transaction;
-use crate::connector::OwnedSession;
+use teo_result::{Error, Result};
+use crate::connector::{ConnectRetry, ConnectionOptions, OperationOptions, OwnedSession};
 use crate::connector::transaction::MongoDBTransaction;
+use crate::connector::url::{redact_url, redact_url_in};
+
+/// Reported to the server when neither the options, the url nor the
+/// namespace name the app, e.g. for the main namespace.
+const DEFAULT_APP_NAME: &str = "teo";
+/// Attempts of asking the primary for the topology, e.g. during an election.
+const TOPOLOGY_PROBE_ATTEMPTS: u32 = 3;
+const TOPOLOGY_PROBE_DELAY: Duration = Duration::from_millis(250);
 
 #[derive(Debug)]
 pub struct MongoDBConnection {
     client: Client,
     database: Database,
     supports_transaction: bool,
+    options: Arc<ConnectionOptions>,
 }
 
 impl MongoDBConnection {
 
     pub async fn new<P>(url: &str, print: P) -> Self where P: Fn(&str) {
+        Self::new_with_options(url, ConnectionOptions::default(), print).await
+    }
+
+    pub async fn new_with_options<P>(url: &str, connection_options: ConnectionOptions, print: P) -> Self where P: Fn(&str) {
+        match Self::try_new_with_options(url, connection_options, print).await {
+            Ok(connection) => connection,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Like `new_with_options`, but returns an error instead of panicking when
+    /// the url is invalid or the server stays unreachable.
+    pub async fn try_new_with_options<P>(url: &str, connection_options: ConnectionOptions, print: P) -> Result<Self> where P: Fn(&str) {
         let options = match ClientOptions::parse(url).await {
             Ok(options) => options,
-            Err(_) => panic!("MongoDB url is invalid.")
+            Err(e) => return Err(Error::new(format!("MongoDB url {} is invalid: {}", redact_url(url), redact_url_in(&e.to_string(), url)))),
         };
         let database_name = match &options.default_database {
-            Some(database_name) => database_name,
-            None => panic!("No database name found in MongoDB url.")
+            Some(database_name) => database_name.clone(),
+            None => return Err(Error::new(format!("No database name found in MongoDB url {}.", redact_url(url)))),
+        };
+        Self::connect(options, &database_name, connection_options, Some(url), print).await
+    }
+
+    /// Connect with client options built programmatically, e.g. for pool
+    /// sizes, TLS files and concerns, instead of encoding them into a url.
+    /// Start from `ClientOptions::parse` to keep the defaults of a url.
+    pub async fn from_options<P>(options: ClientOptions, database_name: &str, connection_options: ConnectionOptions, print: P) -> Self where P: Fn(&str) {
+        match Self::try_from_options(options, database_name, connection_options, print).await {
+            Ok(connection) => connection,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Like `from_options`, but returns an error instead of panicking.
+    pub async fn try_from_options<P>(options: ClientOptions, database_name: &str, connection_options: ConnectionOptions, print: P) -> Result<Self> where P: Fn(&str) {
+        Self::connect(options, database_name, connection_options, None, print).await
+    }
+
+    async fn ping_with_retry<F, Fut, P>(retry: &ConnectRetry, mut ping: F, print: &P) -> std::result::Result<(), String> where F: FnMut() -> Fut, Fut: Future<Output = std::result::Result<(), String>>, P: Fn(&str) {
+        let mut attempt = 1;
+        loop {
+            let result = match retry.attempt_timeout {
+                Some(attempt_timeout) => match tokio::time::timeout(attempt_timeout, ping()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(format!("no server answered within {:?}", attempt_timeout)),
+                },
+                None => ping().await,
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(_) if attempt < retry.max_attempts => {
+                    print(&format!("warning: MongoDB is unreachable, retrying ({}/{})", attempt, retry.max_attempts - 1));
+                    tokio::time::sleep(retry.delay(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn connect<P>(mut options: ClientOptions, database_name: &str, connection_options: ConnectionOptions, url: Option<&str>, print: P) -> Result<Self> where P: Fn(&str) {
+        let redact = |message: String| match url {
+            Some(url) => redact_url_in(&message, url),
+            None => message,
         };
-        let client = match Client::with_options(options.clone()) {
+        Self::apply_app_name(&mut options, &connection_options);
+        let client = match Client::with_options(options) {
             Ok(client) => client,
-            Err(_) => panic!("MongoDB client creating error.")
+            Err(e) => return Err(Error::new(format!("MongoDB client creating error: {}", redact(e.to_string())))),
+        };
+        // the server may come up slightly after the app during orchestrated startups
+        let retry = connection_options.connect_retry.clone().unwrap_or_default();
+        let (client_ref, redact_ref) = (&client, &redact);
+        let ping = move || async move {
+            client_ref.database("xxxxxpingpingpingxxxxx").run_command(doc! {"ping": 1}, None).await.map(|_| ()).map_err(|e| redact_ref(e.to_string()))
         };
-        match client.database("xxxxxpingpingpingxxxxx").run_command(doc! {"ping": 1}, None).await {
-            Ok(_) => (),
-            Err(_) => panic!("Cannot connect to MongoDB database."),
+        if let Err(e) = Self::ping_with_retry(&retry, ping, &print).await {
+            return Err(Error::new(format!("Cannot connect to MongoDB database {}: {}", url.map_or(database_name.to_owned(), redact_url), e)));
         }
 
-        let database = client.database(&database_name);
-        let supports_transaction = Self::test_transaction_support(&client, &database).await;
+        let database = client.database(database_name);
+        let supports_transaction = match connection_options.transaction_support {
+            Some(supports_transaction) => supports_transaction,
+            None => Self::test_transaction_support(&client, &database).await,
+        };
         if !supports_transaction {
             print("warning: MongoDB transaction is not supported in this setup.");
         }
-        Self {
+        Ok(Self {
             client,
             database,
             supports_transaction,
+            options: Arc::new(connection_options),
+        })
+    }
+
+    /// Name the app to the server by `app_name`, else the url's `appName`,
+    /// else the namespace.
+    fn apply_app_name(options: &mut ClientOptions, connection_options: &ConnectionOptions) {
+        if let Some(app_name) = &connection_options.app_name {
+            options.app_name = Some(app_name.clone());
+        } else if options.app_name.is_none() {
+            options.app_name = Some(if connection_options.namespace_path.is_empty() {
+                DEFAULT_APP_NAME.to_owned()
+            } else {
+                connection_options.namespace_path.join(".")
+            });
+        }
+    }
+
+    /// Start a transaction with `options` instead of the connection's
+    /// `transaction_options`.
+    pub async fn transaction_with_options(&self, options: Option<TransactionOptions>) -> Result<Arc<dyn Transaction>> {
+        if !self.supports_transaction {
+            return self.no_transaction().await;
+        }
+        let session = OwnedSession::new(self.client.start_session(None).await.unwrap());
+        session.start_transaction(options).await?;
+        Ok(Arc::new(MongoDBTransaction {
+            owned_session: Some(session),
+            database: self.database.clone(),
+            committed: Arc::new(AtomicBool::new(false)),
+            options: self.options.clone(),
+            operation: OperationOptions::default(),
+        }))
+    }
+
+    /// Transactions need a replica set of MongoDB 4.0+ or a sharded cluster
+    /// of MongoDB 4.2+, told by `hello` on the primary without writing.
+    /// `None` when the primary doesn't answer in time.
+    async fn transaction_support_from_topology(database: &Database) -> Option<bool> {
+        let selection_criteria = SelectionCriteria::ReadPreference(ReadPreference::Primary);
+        for attempt in 0..TOPOLOGY_PROBE_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(TOPOLOGY_PROBE_DELAY).await;
+            }
+            let Ok(hello) = database.run_command(doc! {"hello": 1}, selection_criteria.clone()).await else {
+                continue;
+            };
+            return Some(Self::supports_transaction_by_hello(&hello));
+        }
+        None
+    }
+
+    /// Whether the server which answered `hello` with `hello` supports
+    /// transactions.
+    fn supports_transaction_by_hello(hello: &Document) -> bool {
+        // wire version 7 is MongoDB 4.0, 8 is 4.2
+        let max_wire_version = hello.get_i32("maxWireVersion").unwrap_or(0);
+        if hello.get_str("setName").is_ok() {
+            max_wire_version >= 7
+        } else if hello.get_str("msg") == Ok("isdbgrid") {
+            max_wire_version >= 8
+        } else {
+            false
         }
     }
 
     async fn test_transaction_support(client: &Client, database: &Database) -> bool {
+        if let Some(supports_transaction) = Self::transaction_support_from_topology(database).await {
+            return supports_transaction;
+        }
         let Ok(mut session) = client.start_session(None).await else {
             return false;
         };
@@ -56,12 +201,9 @@ impl MongoDBConnection {
             return false;
         };
         let collection: Collection<Document> = database.collection("__teo__transaction_test__");
-        // match collection.insert_one_with_session(doc! {"supports": true}, None, &mut session).await {
-        //     Ok(_) => (),
-        //     Err(e) => println!("see this error: {:?}", e),
-        // };
         let result = collection.insert_one_with_session(doc! {"supports": true}, None, &mut session).await.is_ok();
-        let Ok(_) = session.commit_transaction().await else {
+        // abort so the probe document is never persisted
+        let Ok(_) = session.abort_transaction().await else {
             return false;
         };
         result
@@ -72,16 +214,7 @@ impl MongoDBConnection {
 impl Connection for MongoDBConnection {
 
     async fn transaction(&self) -> teo_result::Result<Arc<dyn Transaction>> {
-        if !self.supports_transaction {
-            return self.no_transaction().await;
-        }
-        let session = OwnedSession::new(self.client.start_session(None).await.unwrap());
-        session.start_transaction().await?;
-        Ok(Arc::new(MongoDBTransaction {
-            owned_session: Some(session),
-            database: self.database.clone(),
-            committed: Arc::new(AtomicBool::new(false)),
-        }))
+        self.transaction_with_options(self.options.transaction_options.clone()).await
     }
 
     async fn no_transaction(&self) -> teo_result::Result<Arc<dyn Transaction>> {
@@ -89,6 +222,136 @@ impl Connection for MongoDBConnection {
             owned_session: None,
             database: self.database.clone(),
             committed: Arc::new(AtomicBool::new(false)),
+            options: self.options.clone(),
+            operation: OperationOptions::default(),
         }))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+    use bson::doc;
+    use mongodb::Client;
+    use mongodb::options::{ClientOptions, ServerAddress};
+    use crate::connector::{ConnectRetry, ConnectionOptions};
+    use super::MongoDBConnection;
+
+    #[test]
+    fn replica_sets_support_transactions() {
+        assert!(MongoDBConnection::supports_transaction_by_hello(&doc!{"isWritablePrimary": true, "setName": "rs0", "maxWireVersion": 17}));
+        assert!(!MongoDBConnection::supports_transaction_by_hello(&doc!{"isWritablePrimary": true, "setName": "rs0", "maxWireVersion": 6}));
+    }
+
+    #[test]
+    fn sharded_clusters_support_transactions() {
+        assert!(MongoDBConnection::supports_transaction_by_hello(&doc!{"isWritablePrimary": true, "msg": "isdbgrid", "maxWireVersion": 8}));
+        assert!(!MongoDBConnection::supports_transaction_by_hello(&doc!{"isWritablePrimary": true, "msg": "isdbgrid", "maxWireVersion": 7}));
+    }
+
+    #[test]
+    fn standalone_servers_dont_support_transactions() {
+        assert!(!MongoDBConnection::supports_transaction_by_hello(&doc!{"isWritablePrimary": true, "maxWireVersion": 17}));
+    }
+
+    #[tokio::test]
+    async fn unreachable_servers_are_probed_without_support() {
+        let options = ClientOptions::builder()
+            .hosts(vec![ServerAddress::Tcp { host: "127.0.0.1".to_owned(), port: Some(1) }])
+            .server_selection_timeout(Duration::from_millis(50))
+            .build();
+        let client = Client::with_options(options).unwrap();
+        let database = client.database("test");
+        assert_eq!(MongoDBConnection::transaction_support_from_topology(&database).await, None);
+        assert!(!MongoDBConnection::test_transaction_support(&client, &database).await);
+    }
+
+    #[test]
+    fn app_name_is_taken_from_the_options() {
+        let mut options = ClientOptions::builder().app_name("from-url".to_owned()).build();
+        let connection_options = ConnectionOptions { app_name: Some("billing".to_owned()), namespace_path: vec!["shop".to_owned()], ..Default::default() };
+        MongoDBConnection::apply_app_name(&mut options, &connection_options);
+        assert_eq!(options.app_name.as_deref(), Some("billing"));
+    }
+
+    #[test]
+    fn app_name_of_the_url_is_kept() {
+        let mut options = ClientOptions::builder().app_name("from-url".to_owned()).build();
+        let connection_options = ConnectionOptions { namespace_path: vec!["shop".to_owned()], ..Default::default() };
+        MongoDBConnection::apply_app_name(&mut options, &connection_options);
+        assert_eq!(options.app_name.as_deref(), Some("from-url"));
+    }
+
+    #[test]
+    fn app_name_defaults_to_the_namespace() {
+        let mut options = ClientOptions::builder().build();
+        let connection_options = ConnectionOptions { namespace_path: vec!["shop".to_owned(), "billing".to_owned()], ..Default::default() };
+        MongoDBConnection::apply_app_name(&mut options, &connection_options);
+        assert_eq!(options.app_name.as_deref(), Some("shop.billing"));
+        let mut options = ClientOptions::builder().build();
+        MongoDBConnection::apply_app_name(&mut options, &ConnectionOptions::default());
+        assert_eq!(options.app_name.as_deref(), Some("teo"));
+    }
+
+    #[test]
+    fn retry_delay_doubles() {
+        let retry = ConnectRetry { max_attempts: 4, base_delay: Duration::from_millis(100), max_delay: Duration::from_secs(1), attempt_timeout: None };
+        assert_eq!(retry.delay(1), Duration::from_millis(100));
+        assert_eq!(retry.delay(2), Duration::from_millis(200));
+        assert_eq!(retry.delay(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let retry = ConnectRetry { max_attempts: 100, base_delay: Duration::from_millis(100), max_delay: Duration::from_secs(1), attempt_timeout: None };
+        assert_eq!(retry.delay(5), Duration::from_secs(1));
+        assert_eq!(retry.delay(64), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn a_retry_succeeds_after_the_first_ping_fails() {
+        let retry = ConnectRetry { max_attempts: 3, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1), attempt_timeout: None };
+        let counter = AtomicU32::new(0);
+        let pings = &counter;
+        let ping = move || async move {
+            match pings.fetch_add(1, Ordering::SeqCst) {
+                0 => Err("connection refused".to_owned()),
+                _ => Ok(()),
+            }
+        };
+        let warnings = Mutex::new(vec![]);
+        let result = MongoDBConnection::ping_with_retry(&retry, ping, &|message: &str| warnings.lock().unwrap().push(message.to_owned())).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        assert_eq!(*warnings.lock().unwrap(), vec!["warning: MongoDB is unreachable, retrying (1/2)".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn the_last_failure_is_returned() {
+        let retry = ConnectRetry { max_attempts: 2, base_delay: Duration::from_millis(1), max_delay: Duration::from_millis(1), attempt_timeout: None };
+        let ping = || async { Err::<(), _>("connection refused".to_owned()) };
+        let result = MongoDBConnection::ping_with_retry(&retry, ping, &|_: &str| ()).await;
+        assert_eq!(result, Err("connection refused".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn each_attempt_is_bounded_by_its_timeout() {
+        // nothing listens on the port, the driver would keep selecting for 30 seconds
+        let options = ClientOptions::builder()
+            .hosts(vec![ServerAddress::Tcp { host: "127.0.0.1".to_owned(), port: Some(1) }])
+            .server_selection_timeout(Duration::from_secs(30))
+            .build();
+        let connection_options = ConnectionOptions {
+            connect_retry: Some(ConnectRetry { max_attempts: 2, base_delay: Duration::from_millis(10), max_delay: Duration::from_millis(10), attempt_timeout: Some(Duration::from_millis(200)) }),
+            ..Default::default()
+        };
+        let warnings = Mutex::new(vec![]);
+        let started = Instant::now();
+        let result = MongoDBConnection::try_from_options(options, "test", connection_options, |message| warnings.lock().unwrap().push(message.to_owned())).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(warnings.lock().unwrap().len(), 1);
+    }
+}
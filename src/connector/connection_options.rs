@@ -0,0 +1,375 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bson::Document;
+use indexmap::IndexMap;
+use mongodb::options::{Collation, TransactionOptions, WriteConcern};
+use teo_runtime::model::{Index, Model};
+use teo_runtime::model::index::Type as IndexType;
+use teo_runtime::Value;
+
+/// How datetimes carrying sub-millisecond precision are written. BSON
+/// datetimes are millisecond precise, decoded values are always truncated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SubMillisecondPrecision {
+    /// Silently truncate to milliseconds.
+    #[default]
+    Truncate,
+    /// Reject the write instead of losing precision.
+    Reject,
+}
+
+/// How array fields without any element are written. Null values of other
+/// fields are never stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyArray {
+    /// Store `[]`, the field passes `$exists`.
+    #[default]
+    Store,
+    /// Store `null`, the field passes `$exists` but matches `null`.
+    Null,
+    /// Leave the field unset, like null values of other fields.
+    Omit,
+}
+
+/// What saving a record without any changed value does.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EmptyUpdate {
+    /// Don't send any write.
+    #[default]
+    Skip,
+    /// Set the named datetime field to the current time, so the record's
+    /// update time advances. Models without this field are skipped.
+    Touch(String),
+}
+
+/// How stored values which are no member of their enum are decoded, e.g.
+/// after a member was renamed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum UnknownEnumMember {
+    /// Fail the query.
+    #[default]
+    Reject,
+    /// Decode as the fallback member, keyed by enum path joined with `.`, or
+    /// as null on optional fields, warning once per model and enum. Required
+    /// fields of enums without a fallback still fail.
+    Lenient(HashMap<String, String>),
+}
+
+/// What migration does when an index can't be dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexDropFailure {
+    /// Report the failure with the other migration errors and go on,
+    /// leaving the stale index in place.
+    #[default]
+    Continue,
+    /// Stop migrating the collection at the first failure.
+    Abort,
+}
+
+/// What `find_many` does when included relations would be looked up for
+/// more records than `lookup_warning_threshold`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LookupGuard {
+    /// Run the query, raising a warning.
+    #[default]
+    Warn,
+    /// Fail the query before any lookup runs.
+    Reject,
+}
+
+/// Which records without a value a unique index allows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UniqueNulls {
+    /// Sparse index, records missing every indexed field are left out, so
+    /// any number of them is allowed. Explicit nulls are indexed.
+    #[default]
+    Sparse,
+    /// Partial index of the records where every indexed field exists, so
+    /// records missing any of them are left out. Explicit nulls are indexed.
+    PartialExists,
+    /// Every record is indexed, at most one may lack a value.
+    Strict,
+}
+
+/// Retrying the initial ping, doubling the delay after each attempt up to
+/// `max_delay`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRetry {
+    /// Attempts including the first one.
+    pub max_attempts: u32,
+    /// Delay before the second attempt.
+    pub base_delay: Duration,
+    /// Longest delay between two attempts.
+    pub max_delay: Duration,
+    /// How long each attempt waits for a server. `None` waits for the
+    /// client's `serverSelectionTimeout`, 30 seconds by default.
+    pub attempt_timeout: Option<Duration>,
+}
+
+impl ConnectRetry {
+
+    /// The delay after the failed `attempt`, counted from 1.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        match self.base_delay.checked_mul(2u32.saturating_pow(attempt - 1)) {
+            Some(delay) => delay.min(self.max_delay),
+            None => self.max_delay,
+        }
+    }
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        Self { max_attempts: 1, base_delay: Duration::from_millis(500), max_delay: Duration::from_secs(30), attempt_timeout: None }
+    }
+}
+
+/// Receives the warnings raised during operations, e.g. to forward them to
+/// a logger. Defaults to printing them.
+#[derive(Clone)]
+pub struct WarningHandler {
+    handler: Arc<dyn Fn(&str) + Send + Sync>,
+    /// Keys of the warnings issued only once.
+    issued: Arc<Mutex<HashSet<String>>>,
+}
+
+impl WarningHandler {
+
+    pub fn new(handler: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Self { handler: Arc::new(handler), issued: Arc::new(Mutex::new(HashSet::new())) }
+    }
+}
+
+impl Default for WarningHandler {
+    fn default() -> Self {
+        Self::new(|message| println!("{}", message))
+    }
+}
+
+impl Debug for WarningHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WarningHandler")
+    }
+}
+
+/// Options applied to a connection and every transaction spawned from it.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionOptions {
+    /// Skip probing for transaction support when it is known up front.
+    /// `None` probes the server on connect.
+    pub transaction_support: Option<bool>,
+    /// Treatment of datetimes which can't be stored without truncation.
+    pub sub_millisecond_precision: SubMillisecondPrecision,
+    /// Enums stored as integers, keyed by enum path joined with `.` and
+    /// mapping each member name to its stored integer.
+    pub integer_enums: HashMap<String, IndexMap<String, i32>>,
+    /// Answer counts without any filter from collection metadata. Faster on
+    /// huge collections, but may be inaccurate after unclean shutdowns.
+    /// Never used inside a transaction.
+    pub estimated_unfiltered_count: bool,
+    /// How many collections are migrated at once. Defaults to 4.
+    pub migration_concurrency: Option<usize>,
+    /// Operations taking longer than this are reported as warnings.
+    pub slow_query_threshold: Option<Duration>,
+    /// Include the pipeline in slow query warnings.
+    pub slow_query_log_pipeline: bool,
+    /// Suppress warnings raised during operations.
+    pub silent: bool,
+    /// Where warnings raised during operations go.
+    pub warning_handler: WarningHandler,
+    /// After creating a record, read the stored document back so values
+    /// applied by the server are reflected on the object.
+    pub read_back_created: bool,
+    /// The `appName` shown in server logs and `currentOp`, overriding the
+    /// one in the url.
+    pub app_name: Option<String>,
+    /// Path of the namespace the connection serves. Joined with `.`, it's
+    /// the default `appName` when neither `app_name` nor the url sets one.
+    pub namespace_path: Vec<String>,
+    /// Behavior of saves which change no value.
+    pub empty_update: EmptyUpdate,
+    /// Treat object ids and their hex strings as equal when joining relations
+    /// and decoding, for legacy data mixing both representations.
+    pub tolerant_object_ids: bool,
+    /// Sort applied to queries which don't specify `orderBy`, e.g.
+    /// `{"_id": 1}` for stable dumps. `None` leaves order to the server.
+    pub default_sort: Option<Document>,
+    /// Accept `$where` JavaScript predicates in filters. They are passed to
+    /// the server as is, run for every scanned document without using
+    /// indexes, and execute arbitrary code, so never build them from user
+    /// input.
+    pub allow_unsafe_where: bool,
+    /// Collections of models not named after their table name, keyed by
+    /// model path joined with `.`, e.g. for collections of another ODM.
+    pub collection_names: HashMap<String, String>,
+    /// Retry the initial connect, e.g. while the server is still starting.
+    /// `None` gives up after the first failed ping.
+    pub connect_retry: Option<ConnectRetry>,
+    /// Defaults computed by the server on insert from the other stored
+    /// values, keyed by model path joined with `.`, then by field name, e.g.
+    /// `{ "slug": { "$toLower": "$title" } }`. Values given on create win.
+    pub insert_defaults: HashMap<String, IndexMap<String, Value>>,
+    /// Decode `find_many` results of at least this many documents on the
+    /// blocking thread pool, so big reads don't starve the async runtime.
+    pub blocking_decode_threshold: Option<usize>,
+    /// Behavior of migrations failing to drop an index.
+    pub index_drop_failure: IndexDropFailure,
+    /// Fail migrations still running after this long, reporting which
+    /// models completed or failed. Index builds in progress are aborted by
+    /// dropping the index, which MongoDB 4.4+ supports.
+    pub migration_deadline: Option<Duration>,
+    /// Guard `find_many` including relations for at least this many records,
+    /// since each included relation runs a `$lookup` per record. Pages
+    /// without a smaller `take` are counted before the lookups run.
+    pub lookup_warning_threshold: Option<usize>,
+    /// What the `lookup_warning_threshold` guard does.
+    pub lookup_guard: LookupGuard,
+    /// How empty array fields are written.
+    pub empty_array: EmptyArray,
+    /// How many times reads outside a transaction run again after a
+    /// transient network failure, on top of the driver's own single retry.
+    /// Retries wait 100ms, doubling up to 2s, between attempts.
+    pub read_retries: u32,
+    /// Fail `find_unique` when more than one record matches, revealing
+    /// duplicates left by a missing unique index, instead of taking the first.
+    pub strict_find_unique: bool,
+    /// Collation of collections created by migration, their indexes and
+    /// every query, e.g. strength 2 for case-insensitive equality.
+    pub default_collation: Option<Collation>,
+    /// Enable pre- and post-images on collections during migration, so
+    /// watched changes carry the record before the change. MongoDB 6.0+.
+    pub change_stream_pre_and_post_images: bool,
+    /// Decode object ids inside `Any` typed values as hex strings. Fields
+    /// typed as object ids are unaffected.
+    pub stringify_object_ids: bool,
+    /// Fields stored and read back verbatim, keyed by model path and field
+    /// name joined with `.`. Values are read as canonical extended JSON,
+    /// e.g. `{ "$numberLong": "1" }`, and written from it, so every bson
+    /// type survives a round trip exactly, whatever the declared type.
+    pub raw_fields: HashSet<String>,
+    /// Write concerns required by some writes whatever the concern of the
+    /// handle, keyed by model path joined with `.` and then the action,
+    /// `create`, `update` or `delete`, e.g. majority acknowledgment for
+    /// `AuditRecord.delete`. Ignored by the server inside a transaction.
+    pub escalated_write_concerns: HashMap<String, WriteConcern>,
+    /// Options of transactions started by `Connection::transaction`, e.g. a
+    /// snapshot read concern so every read sees the same point in time, or
+    /// `max_commit_time`. `None` uses the client defaults.
+    pub transaction_options: Option<TransactionOptions>,
+    /// Large, rarely needed fields only fetched when selected explicitly,
+    /// keyed by model path and field name joined with `.`. Otherwise they
+    /// are left unset on fetched records.
+    pub lazy_fields: HashSet<String>,
+    /// Accept JavaScript run on the server, `_accumulator` in aggregates and
+    /// `function` in computed values. It executes arbitrary code and must
+    /// also be enabled on the server, so never build it from user input.
+    pub allow_server_js: bool,
+    /// Treatment of stored enum values which are no member of their enum.
+    pub unknown_enum_member: UnknownEnumMember,
+    /// Warn about queries skipping more records than this, since skipped
+    /// records are still scanned. Cursor pagination doesn't have this cost.
+    pub skip_warning_threshold: Option<i64>,
+    /// Refuse queries skipping more records than this, protecting the server
+    /// from deep pagination.
+    pub max_skip: Option<i64>,
+    /// Treatment of records without a value by unique indexes, keyed by
+    /// model path and index name joined with `.`. Defaults to sparse.
+    pub unique_nulls: HashMap<String, UniqueNulls>,
+}
+
+impl ConnectionOptions {
+
+    /// Pass `message` to the warning handler unless `silent` is set.
+    pub(crate) fn warn(&self, message: &str) {
+        if !self.silent {
+            (self.warning_handler.handler)(message);
+        }
+    }
+
+    /// Like `warn`, but only the first warning of each `key` is passed, for
+    /// warnings which would otherwise repeat for every record.
+    pub(crate) fn warn_once(&self, key: String, message: &str) {
+        if !self.silent && self.warning_handler.issued.lock().unwrap().insert(key) {
+            (self.warning_handler.handler)(message);
+        }
+    }
+
+    /// Whether `field_name` of `model` is listed in `raw_fields`.
+    pub(crate) fn is_raw_field(&self, model: &Model, field_name: &str) -> bool {
+        !self.raw_fields.is_empty() && self.raw_fields.contains(&format!("{}.{}", model.path().join("."), field_name))
+    }
+
+    /// Whether `field_name` of `model` is listed in `lazy_fields`.
+    pub(crate) fn is_lazy_field(&self, model: &Model, field_name: &str) -> bool {
+        !self.lazy_fields.is_empty() && self.lazy_fields.contains(&format!("{}.{}", model.path().join("."), field_name))
+    }
+
+    /// The write concern `escalated_write_concerns` requires for `action`
+    /// on records of `model`.
+    pub(crate) fn escalated_write_concern(&self, model: &Model, action: &str) -> Option<&WriteConcern> {
+        if self.escalated_write_concerns.is_empty() {
+            return None;
+        }
+        self.escalated_write_concerns.get(&format!("{}.{}", model.path().join("."), action))
+    }
+
+    /// How `index` of `model` treats records without a value. Indexes other
+    /// than unique ones are always sparse.
+    pub(crate) fn unique_nulls(&self, model: &Model, index: &Index) -> UniqueNulls {
+        if index.r#type() != IndexType::Unique {
+            return UniqueNulls::Sparse;
+        }
+        self.unique_nulls.get(&format!("{}.{}", model.path().join("."), index.name())).copied().unwrap_or_default()
+    }
+
+    /// The collection storing the records of `model`.
+    pub(crate) fn collection_name<'a>(&'a self, model: &'a Model) -> &'a str {
+        match self.collection_names.get(&model.path().join(".")) {
+            Some(name) => name.as_str(),
+            None => model.table_name(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use super::{ConnectionOptions, WarningHandler};
+
+    fn recording_options() -> (ConnectionOptions, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let sink = received.clone();
+        let options = ConnectionOptions {
+            warning_handler: WarningHandler::new(move |message| sink.lock().unwrap().push(message.to_owned())),
+            ..Default::default()
+        };
+        (options, received)
+    }
+
+    #[test]
+    fn warnings_reach_the_handler() {
+        let (options, received) = recording_options();
+        options.warn("first");
+        options.warn("first");
+        assert_eq!(*received.lock().unwrap(), vec!["first", "first"]);
+    }
+
+    #[test]
+    fn warnings_keyed_once_are_passed_once() {
+        let (options, received) = recording_options();
+        options.warn_once("a".to_owned(), "a");
+        options.warn_once("a".to_owned(), "a");
+        options.warn_once("b".to_owned(), "b");
+        assert_eq!(*received.lock().unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn silent_suppresses_warnings() {
+        let (mut options, received) = recording_options();
+        options.silent = true;
+        options.warn("ignored");
+        options.warn_once("ignored".to_owned(), "ignored");
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
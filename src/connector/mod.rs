@@ -1,7 +1,14 @@
 pub mod connection;
 pub mod transaction;
 pub mod owned_session;
+pub mod operation_options;
+pub mod connection_options;
+pub mod model_change;
+pub mod url;
 
 pub use connection::MongoDBConnection;
 pub use transaction::MongoDBTransaction;
-pub use owned_session::OwnedSession;
\ No newline at end of file
+pub use owned_session::OwnedSession;
+pub use operation_options::{FractionalMultiplier, OperationOptions};
+pub use model_change::ModelChange;
+pub use connection_options::{ConnectRetry, ConnectionOptions, EmptyArray, EmptyUpdate, IndexDropFailure, LookupGuard, SubMillisecondPrecision, UniqueNulls, UnknownEnumMember, WarningHandler};
@@ -0,0 +1,13 @@
+use mongodb::change_stream::event::OperationType;
+use teo_runtime::model::object::Object;
+
+/// A change of a record observed through a change stream.
+#[derive(Debug, Clone)]
+pub struct ModelChange {
+    /// Kind of the change, e.g. insert, update or delete.
+    pub operation: OperationType,
+    /// The record before the change, when pre-images are enabled.
+    pub before: Option<Object>,
+    /// The record after the change, absent for deletes.
+    pub after: Option<Object>,
+}
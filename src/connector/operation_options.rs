@@ -0,0 +1,32 @@
+use mongodb::options::{Hint, ReadConcern, ReadPreference, WriteConcern};
+use teo_runtime::value::Value;
+
+/// How integer fields multiplied or divided into fractions are updated.
+/// Integer fields can't store the fraction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FractionalMultiplier {
+    /// Fail the update.
+    #[default]
+    Reject,
+    /// Round the product half away from zero, keeping the integer type.
+    Round,
+}
+
+/// Options scoped to the operations issued through a single transaction handle.
+#[derive(Debug, Clone, Default)]
+pub struct OperationOptions {
+    /// Attached to every command so it shows up in `db.currentOp()` and the profiler.
+    pub comment: Option<String>,
+    /// Overrides the write concern of the collections written to.
+    pub write_concern: Option<WriteConcern>,
+    /// Overrides the read concern of the collections read from.
+    pub read_concern: Option<ReadConcern>,
+    /// Overrides the read preference of the collections read from.
+    pub read_preference: Option<ReadPreference>,
+    /// Index hint for reads, useful to speed up filtered counts.
+    pub hint: Option<Hint>,
+    /// Extra `where` input an updated record must still match.
+    pub update_guard: Option<Value>,
+    /// Treatment of integer fields multiplied or divided into fractions.
+    pub fractional_multiplier: FractionalMultiplier,
+}
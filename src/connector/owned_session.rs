@@ -1,5 +1,6 @@
 use std::sync::Arc;
 use mongodb::ClientSession;
+use mongodb::options::TransactionOptions;
 use teo_result::{Result, Error};
 
 #[derive(Debug)]
@@ -42,8 +43,8 @@ impl OwnedSession {
         self.inner.session_mut()
     }
 
-    pub async fn start_transaction(&self) -> Result<()> {
-        match self.inner.session_mut().start_transaction(None).await {
+    pub async fn start_transaction(&self, options: Option<TransactionOptions>) -> Result<()> {
+        match self.inner.session_mut().start_transaction(options).await {
             Ok(_) => Ok(()),
             Err(e) => Err(Error::new(e.to_string())),
         }
@@ -1,21 +1,30 @@
 use std::fmt::{Debug};
 use std::ops::Neg;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use async_trait::async_trait;
+use bigdecimal::{BigDecimal, FromPrimitive, One, Signed, ToPrimitive, Zero};
 use bson::{Bson, doc, Document};
+use bson::oid::ObjectId;
+use chrono::{SubsecRound, Utc};
 use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use indexmap::IndexMap;
+use itertools::Itertools;
 use key_path::{KeyPath, path};
 use mongodb::{Database, Collection, IndexModel, ClientSession};
-use mongodb::error::{ErrorKind, WriteFailure, Error as MongoDBError};
-use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument};
+use mongodb::error::{BulkWriteFailure, ErrorKind, WriteFailure, Error as MongoDBError};
+use mongodb::change_stream::event::ChangeStreamEvent;
+use mongodb::options::{AggregateOptions, ChangeStreamOptions, ChangeStreamPreAndPostImages, CollectionOptions, CreateCollectionOptions, FullDocumentBeforeChangeType, FullDocumentType, DeleteOptions, FindOneAndUpdateOptions, IndexOptions, InsertManyOptions, InsertOneOptions, Hint, ReadConcern, ReadPreference, ReturnDocument, SelectionCriteria, UpdateOptions, WriteConcern};
 use regex::Regex;
 use crate::aggregation::Aggregation;
 use crate::bson_ext::coder::BsonCoder;
 use teo_runtime::action::action::*;
 use teo_runtime::model::object::Object;
 use teo_runtime::model::index::Type;
-use teo_runtime::model::{Index, Model};
+use teo_runtime::model::{Field, Index, Model};
 use teo_runtime::value::Value;
 use teo_result::{Error, Result};
 use teo_runtime::connection::transaction::{Ctx, Transaction};
@@ -30,19 +39,114 @@ use teo_runtime::error_ext;
 use teo_runtime::request::Request;
 use teo_runtime::utils::ContainsStr;
 use teo_runtime::teon;
-use crate::bson_ext::teon_value_to_bson;
-use crate::connector::OwnedSession;
+use teo_parser::r#type::Type as FieldType;
+use crate::bson_ext::{bson_to_teon_value, decimal_to_bson, document_to_teon_value, raw_bson_to_value, raw_value_to_bson, teon_value_to_bson};
+use crate::connector::{ConnectionOptions, EmptyArray, EmptyUpdate, FractionalMultiplier, IndexDropFailure, LookupGuard, ModelChange, OperationOptions, OwnedSession, UniqueNulls};
 use crate::migration::index_model::FromIndexModel;
 
+const DEFAULT_MIGRATION_CONCURRENCY: usize = 4;
+/// The largest document the server stores.
+const MAX_DOCUMENT_SIZE: usize = 16 * 1024 * 1024;
+/// Server error code of creating a collection which exists.
+const NAMESPACE_EXISTS: i32 = 48;
+/// Server error code of modifying a collection which doesn't exist.
+const NAMESPACE_NOT_FOUND: i32 = 26;
+/// Wait before the first read retry, doubled for each following one.
+const READ_RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+/// The longest wait between read retries.
+const READ_RETRY_MAX_DELAY: Duration = Duration::from_secs(2);
+/// Most ids in the filter of one relation count update.
+const RELATION_COUNT_UPDATE_BATCH: usize = 10_000;
+
+/// How an exactly computed update scales the stored value.
+enum Factor {
+    Times(BigDecimal),
+    Over(BigDecimal),
+}
+
 #[derive(Debug, Clone)]
 pub struct MongoDBTransaction {
     pub(super) database: Database,
     pub(super) owned_session: Option<OwnedSession>,
     pub committed: Arc<AtomicBool>,
+    pub(super) options: Arc<ConnectionOptions>,
+    pub(super) operation: OperationOptions,
 }
 
 impl MongoDBTransaction {
 
+    pub fn with_comment(&self, comment: impl Into<String>) -> Self {
+        let mut transaction = self.clone();
+        transaction.operation.comment = Some(comment.into());
+        transaction
+    }
+
+    /// Operations outside a transaction use `write_concern` instead of the
+    /// connection default. Ignored by the server inside a transaction.
+    pub fn with_write_concern(&self, write_concern: WriteConcern) -> Self {
+        let mut transaction = self.clone();
+        transaction.operation.write_concern = Some(write_concern);
+        transaction
+    }
+
+    pub fn with_read_concern(&self, read_concern: ReadConcern) -> Self {
+        let mut transaction = self.clone();
+        transaction.operation.read_concern = Some(read_concern);
+        transaction
+    }
+
+    /// Reads issued through the returned handle are routed by
+    /// `read_preference`, e.g. to secondaries for analytics tolerating
+    /// staleness bounded by `max_staleness`. Reads inside a transaction must
+    /// use the primary.
+    pub fn with_read_preference(&self, read_preference: ReadPreference) -> Self {
+        let mut transaction = self.clone();
+        transaction.operation.read_preference = Some(read_preference);
+        transaction
+    }
+
+    /// Updates issued through the returned handle only apply while the record
+    /// also matches `guard`, a `where` input on fields of the updated model.
+    /// Relation conditions are rejected.
+    pub fn with_update_guard(&self, guard: Value) -> Self {
+        let mut transaction = self.clone();
+        transaction.operation.update_guard = Some(guard);
+        transaction
+    }
+
+    /// Updates issued through the returned handle treat integer fields
+    /// multiplied or divided into fractions as `fractional_multiplier` tells.
+    pub fn with_fractional_multiplier(&self, fractional_multiplier: FractionalMultiplier) -> Self {
+        let mut transaction = self.clone();
+        transaction.operation.fractional_multiplier = fractional_multiplier;
+        transaction
+    }
+
+    fn comment_bson(&self) -> Option<Bson> {
+        self.operation.comment.as_ref().map(|comment| Bson::String(comment.clone()))
+    }
+
+    /// Reads issued through the returned handle use the index `hint`.
+    pub fn with_hint(&self, hint: Hint) -> Self {
+        let mut transaction = self.clone();
+        transaction.operation.hint = Some(hint);
+        transaction
+    }
+
+    fn aggregate_options(&self) -> AggregateOptions {
+        let mut options = AggregateOptions::default();
+        options.collation = self.options.default_collation.clone();
+        options.comment = self.operation.comment.clone();
+        options.hint = self.operation.hint.clone();
+        options
+    }
+
+    fn is_unfiltered(finder: &Value) -> bool {
+        let filtered = ["skip", "take", "cursor", "pageSize", "pageNumber", "distinct"].iter().any(|k| finder.get(k).is_some());
+        let has_where = finder.get("where").map_or(false, |w| !w.as_dictionary().unwrap().is_empty());
+        !filtered && !has_where
+    }
+
     pub(crate) fn session(&self) -> Option<&mut ClientSession> {
         if self.committed.load(Ordering::SeqCst) {
             None
@@ -54,20 +158,72 @@ impl MongoDBTransaction {
         }
     }
 
+    /// The `(major, minor)` version of the connected server.
+    pub async fn server_version(&self) -> Result<(i32, i32)> {
+        let build_info = match self.database.run_command(doc!{"buildInfo": 1}, None).await {
+            Ok(build_info) => build_info,
+            Err(err) => return Err(Error::new(format!("cannot read MongoDB server version: {}", err))),
+        };
+        let version_array = build_info.get_array("versionArray").map_err(|_| Error::new("cannot read MongoDB server version"))?;
+        let major = version_array.get(0).and_then(|v| v.as_i32()).unwrap_or(0);
+        let minor = version_array.get(1).and_then(|v| v.as_i32()).unwrap_or(0);
+        Ok((major, minor))
+    }
+
+    async fn require_server_version(&self, major: i32, minor: i32, feature: &str) -> Result<()> {
+        if self.server_version().await? < (major, minor) {
+            Err(Error::new(format!("{} requires MongoDB {}.{} or later", feature, major, minor)))
+        } else {
+            Ok(())
+        }
+    }
+
     pub(crate) fn get_collection(&self, model: &Model) -> Collection<Document> {
-        self.database.collection(model.table_name())
+        self.get_collection_named(self.options.collection_name(model))
+    }
+
+    fn get_collection_named(&self, name: &str) -> Collection<Document> {
+        let options = CollectionOptions::builder()
+            .write_concern(self.operation.write_concern.clone())
+            .read_concern(self.operation.read_concern.clone())
+            .selection_criteria(self.operation.read_preference.clone().map(SelectionCriteria::ReadPreference))
+            .build();
+        self.database.collection_with_options(name, options)
+    }
+
+    /// The collection `action` writes records of `model` to, with the write
+    /// concern escalated if the connection requires it for this action.
+    fn get_write_collection(&self, model: &Model, action: &str) -> Collection<Document> {
+        match self.options.escalated_write_concern(model, action) {
+            Some(write_concern) => self.with_write_concern(write_concern.clone()).get_collection(model),
+            None => self.get_collection(model),
+        }
     }
 
-    fn document_to_object(&self, transaction_ctx: Ctx, document: &Document, object: &Object, select: Option<&Value>, include: Option<&Value>) -> Result<()> {
+    /// Decode `document` onto `object`. Related objects keep the order of the
+    /// `$lookup` output, which follows the relation's `orderBy`, and
+    /// dictionaries keep the order their keys are stored in.
+    fn document_to_object(&self, transaction_ctx: Ctx, document: &Document, object: &Object, select: Option<&Value>, include: Option<&Value>, aliases: &IndexMap<String, String>) -> Result<()> {
         for key in document.keys() {
+            if let Some(target) = aliases.get(key) {
+                // computed
+                let bson_value = document.get(key).unwrap();
+                if let Some(field) = object.model().field(target) {
+                    let value = BsonCoder::decode(&self.options, transaction_ctx.namespace(), object.model(), field.r#type(), field.is_optional(), bson_value, path![])?;
+                    object.set_value(target, value)?;
+                } else if let Some(property) = object.model().property(target) {
+                    let value = BsonCoder::decode(&self.options, transaction_ctx.namespace(), object.model(), property.r#type(), property.r#type().is_optional(), bson_value, path![])?;
+                    object.inner.cached_property_map.lock().unwrap().insert(target.clone(), value);
+                }
+                continue;
+            }
             let object_field = object.model().fields().values().find(|f| f.column_name() == key);
             if object_field.is_some() {
                 // field
                 let object_field = object_field.unwrap();
                 let object_key = object_field.name();
-                let r#type = object_field.r#type();
                 let bson_value = document.get(key).unwrap();
-                let value_result = BsonCoder::decode(transaction_ctx.namespace(), object.model(), r#type, object_field.is_optional(), bson_value, path![]);
+                let value_result = self.decode_field(transaction_ctx.namespace(), object.model(), object_field, bson_value);
                 match value_result {
                     Ok(value) => {
                         object.set_value(object_key, value).unwrap();
@@ -99,12 +255,20 @@ impl MongoDBTransaction {
                 };
                 let relation = relation.unwrap();
                 let relation_model = transaction_ctx.namespace().model_at_path(&relation.model_path()).unwrap();
-                let object_bsons = document.get(key).unwrap().as_array().unwrap();
+                // to-one relations may come back as a single document or null
+                let object_bsons: Vec<&Bson> = match document.get(key).unwrap() {
+                    Bson::Array(array) => array.iter().collect(),
+                    Bson::Null => vec![],
+                    bson => vec![bson],
+                };
                 let mut related: Vec<Object> = vec![];
                 for related_object_bson in object_bsons {
                     let action = NESTED | FIND | (if relation.is_vec() { MANY } else { SINGLE });
                     let related_object = transaction_ctx.new_object(relation_model, action, object.request())?;
-                    self.clone().document_to_object(transaction_ctx.clone(), related_object_bson.as_document().unwrap(), &related_object, inner_select, inner_include)?;
+                    let Some(related_document) = related_object_bson.as_document() else {
+                        return Err(error_ext::record_decoding_error(object.model().name(), path![key.as_str()], "document"));
+                    };
+                    self.clone().document_to_object(transaction_ctx.clone(), related_document, &related_object, inner_select, inner_include, &Aggregation::nested_aliases(related_document))?;
                     related.push(related_object);
                 }
                 object.inner.relation_query_map.lock().unwrap().insert(key.to_string(), related);
@@ -159,118 +323,306 @@ impl MongoDBTransaction {
     }
 
     async fn aggregate_to_documents(&self, aggregate_input: Vec<Document>, col: Collection<Document>, path: KeyPath) -> Result<Vec<std::result::Result<Document, MongoDBError>>> {
-        match self.session() {
-            Some(session) => {
-                let cur = col.aggregate_with_session(aggregate_input, None, session).await;
-                if cur.is_err() {
-                    return Err(error_ext::unknown_database_find_error(path, format!("{:?}", cur)));
-                }
-                let mut cur = cur.unwrap();
-                let mut results: Vec<std::result::Result<Document, MongoDBError>> = vec![];
-                loop {
-                    if let Some(item) = cur.next(session).await {
+        let started = Instant::now();
+        let logged_pipeline = if self.options.slow_query_log_pipeline { Some(aggregate_input.clone()) } else { None };
+        let results = match self.session() {
+            // failed queries are reported too, e.g. those exceeding `maxTimeMS`
+            Some(session) => match col.aggregate_with_session(aggregate_input, self.aggregate_options(), session).await {
+                Ok(mut cur) => {
+                    let mut results: Vec<std::result::Result<Document, MongoDBError>> = vec![];
+                    while let Some(item) = cur.next(session).await {
                         results.push(item);
-                    } else {
-                        break;
                     }
+                    Ok(results)
                 }
-                Ok(results)
+                Err(err) => Err(error_ext::unknown_database_find_error(path, format!("{:?}", err))),
             },
             None => {
-                let cur = col.aggregate(aggregate_input, None).await;
-                if cur.is_err() {
-                    return Err(error_ext::unknown_database_find_error(path, format!("{:?}", cur)));
+                // reads are idempotent, outside a transaction they can simply run again
+                let mut attempt = 0;
+                loop {
+                    let cur = col.aggregate(aggregate_input.clone(), self.aggregate_options()).await;
+                    let cur = match cur {
+                        Ok(cur) => cur,
+                        Err(err) if attempt < self.options.read_retries && Self::is_transient(&err) => {
+                            attempt += 1;
+                            tokio::time::sleep(Self::read_retry_delay(attempt)).await;
+                            continue;
+                        }
+                        Err(err) => break Err(error_ext::unknown_database_find_error(path, format!("{:?}", err))),
+                    };
+                    let results: Vec<std::result::Result<Document, MongoDBError>> = cur.collect().await;
+                    if attempt < self.options.read_retries && results.iter().any(|r| r.as_ref().is_err_and(Self::is_transient)) {
+                        attempt += 1;
+                        tokio::time::sleep(Self::read_retry_delay(attempt)).await;
+                        continue;
+                    }
+                    break Ok(results);
                 }
-                let cur = cur.unwrap();
-                let results: Vec<std::result::Result<Document, MongoDBError>> = cur.collect().await;
-                Ok(results)
             },
+        };
+        self.report_slow_query("aggregate", col.name(), started, logged_pipeline.as_ref());
+        results
+    }
+
+    /// The wait before read retry `attempt`, counted from 1, giving a
+    /// failing over replica set time to elect a primary.
+    fn read_retry_delay(attempt: u32) -> Duration {
+        READ_RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt - 1)).min(READ_RETRY_MAX_DELAY)
+    }
+
+    /// Whether `error` is caused by a transient network or topology failure,
+    /// so the operation may succeed when retried.
+    fn is_transient(error: &MongoDBError) -> bool {
+        matches!(*error.kind, ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } | ErrorKind::ServerSelection { .. })
+            || error.contains_label("TransientTransactionError")
+            || error.contains_label("RetryableWriteError")
+    }
+
+    /// Decode aggregate results leniently, for rows not shaped like a model.
+    /// The collection name and the pipeline of a `query_raw` input.
+    fn raw_query_input(value: &Value) -> Result<(&str, Vec<Document>)> {
+        let Some(collection) = value.get("collection").and_then(|c| c.as_str()) else {
+            return Err(Error::new("raw query requires a collection name"));
+        };
+        let Some(pipeline) = value.get("pipeline").and_then(|p| p.as_array()) else {
+            return Err(Error::new("raw query requires a pipeline array"));
+        };
+        let mut aggregate_input = vec![];
+        for stage in pipeline {
+            match teon_value_to_bson(stage)? {
+                Bson::Document(stage) => aggregate_input.push(stage),
+                _ => return Err(Error::new("raw query pipeline stages must be dictionaries")),
+            }
+        }
+        Ok((collection, aggregate_input))
+    }
+
+    fn documents_to_values(results: Vec<std::result::Result<Document, MongoDBError>>, path: KeyPath) -> Result<Vec<Value>> {
+        let mut retval = vec![];
+        for result in results {
+            match result {
+                Ok(document) => retval.push(document_to_teon_value(&document)),
+                Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            }
+        }
+        Ok(retval)
+    }
+
+    fn documents_to_objects(&self, results: Vec<std::result::Result<Document, MongoDBError>>, model: &Model, select: Option<&Value>, include: Option<&Value>, aliases: &IndexMap<String, String>, reverse: bool, action: Action, transaction_ctx: Ctx, request: Option<Request>, path: KeyPath) -> Result<Vec<Object>> {
+        let mut result = vec![];
+        for doc in results {
+            let obj = transaction_ctx.new_object(model, action, request.clone())?;
+            match self.clone().document_to_object(transaction_ctx.clone(), &doc.unwrap(), &obj, select, include, aliases) {
+                Ok(_) => {
+                    if reverse {
+                        result.insert(0, obj);
+                    } else {
+                        result.push(obj);
+                    }
+                }
+                Err(err) => {
+                    return Err(error_ext::unknown_database_find_error(path, format!("{}", err)));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    fn report_slow_query(&self, operation: &str, collection: &str, started: Instant, pipeline: Option<&Vec<Document>>) {
+        let Some(threshold) = self.options.slow_query_threshold else {
+            return
+        };
+        let elapsed = started.elapsed();
+        if elapsed < threshold {
+            return
+        }
+        match pipeline {
+            Some(pipeline) => self.options.warn(&format!("warning: slow MongoDB {} on {} took {:?}: {:?}", operation, collection, elapsed, pipeline)),
+            None => self.options.warn(&format!("warning: slow MongoDB {} on {} took {:?}", operation, collection, elapsed)),
         }
     }
 
     async fn aggregate_or_group_by(&self, namespace: &Namespace, model: &Model, finder: &Value, path: KeyPath) -> Result<Vec<Value>> {
-        let aggregate_input = Aggregation::build_for_aggregate(namespace, model, finder)?;
+        let aggregate_input = Aggregation::build_for_aggregate(&self.options, namespace, model, finder)?;
         let col = self.get_collection(model);
         let results = self.aggregate_to_documents(aggregate_input, col, path).await?;
         let mut final_retval: Vec<Value> = vec![];
         for result in results.iter() {
             // there are records
             let data = result.as_ref().unwrap();
-            let mut retval = teon!({});
-            for (g, o) in data {
-                if g.as_str() == "_id" {
-                    continue;
+            final_retval.push(self.decode_aggregate_row(namespace, model, data)?);
+        }
+        Ok(final_retval)
+    }
+
+    /// Like `group_by`, but rows are decoded lazily while the cursor is
+    /// consumed instead of being buffered.
+    pub async fn group_by_stream<'a>(&'a self, namespace: &'a Namespace, model: &'a Model, finder: &Value, batch_size: u32, allow_disk_use: bool, path: KeyPath) -> Result<BoxStream<'a, Result<Value>>> {
+        let aggregate_input = Aggregation::build_for_aggregate(&self.options, namespace, model, finder)?;
+        let col = self.get_collection(model);
+        let mut options = self.aggregate_options();
+        options.batch_size = Some(batch_size);
+        options.allow_disk_use = Some(allow_disk_use);
+        let documents: BoxStream<'a, std::result::Result<Document, MongoDBError>> = match self.session() {
+            Some(session) => {
+                let cursor = match col.aggregate_with_session(aggregate_input, options, session).await {
+                    Ok(cursor) => cursor,
+                    Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{:?}", err))),
+                };
+                Box::pin(futures_util::stream::unfold((cursor, session), |(mut cursor, session)| async move {
+                    cursor.next(session).await.map(|item| (item, (cursor, session)))
+                }))
+            }
+            None => match col.aggregate(aggregate_input, options).await {
+                Ok(cursor) => Box::pin(cursor),
+                Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{:?}", err))),
+            }
+        };
+        Ok(Box::pin(documents.map(move |result| match result {
+            Ok(data) => self.decode_aggregate_row(namespace, model, &data),
+            Err(err) => Err(error_ext::unknown_database_find_error(path.clone(), format!("{}", err))),
+        })))
+    }
+
+    fn decode_aggregate_row(&self, namespace: &Namespace, model: &Model, data: &Document) -> Result<Value> {
+        let mut retval = teon!({});
+        for (g, o) in data {
+            if g.as_str() == "_id" {
+                let components = Self::group_key_components(o, |column_name| model.fields().values().find(|f| f.column_name() == column_name));
+                for (field, component) in components {
+                    let value = self.decode_group_key(namespace, model, field, component)?;
+                    retval.as_dictionary_mut().unwrap().insert(field.name().to_string(), value);
                 }
-                // aggregate
-                if g.starts_with("_") {
-                    retval.as_dictionary_mut().unwrap().insert(g.clone(), teon!({}));
-                    for (dbk, v) in o.as_document().unwrap() {
-                        let k = dbk;
-                        if let Some(f) = v.as_f64() {
-                            retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), teon!(f));
-                        } else if let Some(i) = v.as_i64() {
-                            retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), teon!(i));
-                        } else if let Some(i) = v.as_i32() {
-                            retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), teon!(i));
-                        } else if v.as_null().is_some() {
-                            retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), teon!(null));
-                        }
+                continue;
+            }
+            if retval.as_dictionary().unwrap().contains_key(g.as_str()) {
+                continue;
+            }
+            if g.as_str() == "_accumulator" {
+                // results of server side JavaScript are kept as they are
+                let Some(value) = bson_to_teon_value(o) else {
+                    return Err(error_ext::record_decoding_error(model.name(), path![g.as_str()], "accumulator result"));
+                };
+                retval.as_dictionary_mut().unwrap().insert(g.clone(), value);
+                continue;
+            }
+            // aggregate
+            if g.starts_with("_") {
+                retval.as_dictionary_mut().unwrap().insert(g.clone(), teon!({}));
+                for (dbk, v) in o.as_document().unwrap() {
+                    let k = dbk;
+                    if let Some(f) = v.as_f64() {
+                        retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), teon!(f));
+                    } else if let Some(i) = v.as_i64() {
+                        retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), teon!(i));
+                    } else if let Some(i) = v.as_i32() {
+                        retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), teon!(i));
+                    } else if v.as_null().is_some() {
+                        retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), teon!(null));
+                    } else if let Some(elements) = v.as_array() {
+                        // `_push` and `_addToSet` collect values of the field
+                        let Some(field) = model.field(k) else {
+                            continue;
+                        };
+                        let values = elements.iter().map(|element| self.decode_group_key(namespace, model, field, element)).collect::<Result<Vec<Value>>>()?;
+                        retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), Value::Array(values));
                     }
-                } else {
-                    // group by field
-                    let field = model.field(g).unwrap();
-                    let val = if o.as_null().is_some() { Value::Null } else {
-                        BsonCoder::decode(namespace, model, field.r#type(), true, o, path![])?
-                    };
-                    let json_val = val;
-                    retval.as_dictionary_mut().unwrap().insert(g.to_string(), json_val);
                 }
+            } else {
+                // group by field
+                let field = model.field(g).unwrap();
+                let json_val = self.decode_group_key(namespace, model, field, o)?;
+                retval.as_dictionary_mut().unwrap().insert(g.to_string(), json_val);
             }
-            final_retval.push(retval);
         }
-        Ok(final_retval)
+        Ok(retval)
     }
 
-    async fn create_object(&self, object: &Object, path: KeyPath) -> Result<()> {
-        let namespace = object.namespace();
+    /// The components of a compound group `_id`, each with the field of its
+    /// column, in grouping order. `null` when aggregating without `by`.
+    fn group_key_components<'a, F>(id: &'a Bson, field_of: impl Fn(&str) -> Option<F>) -> Vec<(F, &'a Bson)> {
+        let Some(components) = id.as_document() else {
+            return vec![];
+        };
+        components.iter().filter_map(|(column_name, component)| Some((field_of(column_name)?, component))).collect()
+    }
+
+    fn decode_group_key(&self, namespace: &Namespace, model: &Model, field: &Field, bson: &Bson) -> Result<Value> {
+        Ok(if bson.as_null().is_some() { Value::Null } else if let Bson::Decimal128(decimal) = bson {
+            // decimal group keys are grouped by `$toDecimal`
+            match BigDecimal::from_str(&decimal.to_string()) {
+                Ok(decimal) => Value::Decimal(decimal),
+                Err(_) => return Err(error_ext::record_decoding_error(model.name(), path![field.name()], "decimal")),
+            }
+        } else {
+            BsonCoder::decode(&self.options, namespace, model, field.r#type(), true, bson, path![])?
+        })
+    }
+
+    /// The document inserted for a new `object`.
+    async fn object_document(&self, object: &Object) -> Result<Document> {
         let model = object.model();
         let keys = object.keys_for_save();
-        let col = self.get_collection(model);
-        let auto_keys = &model.cache().auto_keys;
-        // create
         let mut doc = doc!{};
         for key in keys {
+            let column_name = Self::save_column(model, key);
             if let Some(field) = model.field(key) {
-                let column_name = field.column_name();
-                let val: Bson = BsonCoder::encode(field.r#type(), object.get_value(&key).unwrap())?;
-                if val != Bson::Null {
-                    doc.insert(column_name, val);
+                let val: Bson = self.encode_field(model, field, object.get_value(&key).unwrap())?;
+                if let Some(val) = Self::stored_field_value(self.options.empty_array, val) {
+                    Self::insert_unique(&mut doc, column_name, val, key)?;
                 }
             } else if let Some(property) = model.property(key) {
-                let val: Bson = BsonCoder::encode(property.r#type(), object.get_property_value(&key).await?)?;
+                let val: Bson = BsonCoder::encode(&self.options, property.r#type(), object.get_property_value(&key).await?)?;
                 if val != Bson::Null {
-                    doc.insert(key, val);
+                    Self::insert_unique(&mut doc, column_name, val, key)?;
                 }
             }
         }
-        let result = match self.session() {
-            Some(session) => {
-                col.insert_one_with_session(doc, None, session).await
-            }
+        Self::check_document_size(model, &doc)?;
+        Ok(doc)
+    }
+
+    async fn create_object(&self, object: &Object, path: KeyPath) -> Result<()> {
+        let namespace = object.namespace();
+        let model = object.model();
+        let col = self.get_write_collection(model, "create");
+        let auto_keys = &model.cache().auto_keys;
+        // create
+        let doc = self.object_document(object).await?;
+        let computed_defaults = self.options.insert_defaults.get(&model.path().join("."));
+        let started = Instant::now();
+        let result = match computed_defaults {
+            Some(computed_defaults) => self.insert_with_computed_defaults(model, &col, doc, computed_defaults).await,
             None => {
-                col.insert_one(doc, None).await
+                let options = InsertOneOptions::builder().comment(self.comment_bson()).build();
+                match self.session() {
+                    Some(session) => {
+                        col.insert_one_with_session(doc, options, session).await
+                    }
+                    None => {
+                        col.insert_one(doc, options).await
+                    }
+                }.map(|insert_one_result| insert_one_result.inserted_id)
             }
         };
+        self.report_slow_query("insert", col.name(), started, None);
         match result {
-            Ok(insert_one_result) => {
-                let id = insert_one_result.inserted_id;
+            Ok(id) => {
+                let mut server_generated = computed_defaults.is_some();
                 for key in auto_keys {
                     let field = model.field(key).unwrap();
                     if field.column_name() == "_id" {
-                        let new_value = BsonCoder::decode(namespace, model, field.r#type(), field.is_optional(), &id, path![]).unwrap();
+                        let new_value = BsonCoder::decode(&self.options, namespace, model, field.r#type(), field.is_optional(), &id, path![]).unwrap();
                         object.set_value(field.name(), new_value)?;
+                    } else {
+                        server_generated = true;
                     }
                 }
+                // other auto keys are only known to the server
+                if self.options.read_back_created || server_generated {
+                    self.read_back(object, &col, doc!{"_id": id}, path).await?;
+                }
             }
             Err(error) => {
                 return Err(self._handle_write_error(&error.kind, object, path));
@@ -279,45 +631,299 @@ impl MongoDBTransaction {
         Ok(())
     }
 
+    /// Insert records of one model with a single `insertMany`. Ordered
+    /// batches stop at the first failure, storing the records before it and
+    /// none after. Unordered batches attempt every record. The error names
+    /// the index of each record which failed, and stored records get their ids
+    /// and server applied values. Models with computed defaults are rejected.
+    pub async fn create_many(&self, objects: &[Object], ordered: bool, path: KeyPath) -> Result<()> {
+        let Some(first) = objects.first() else {
+            return Ok(());
+        };
+        let namespace = first.namespace();
+        let model = first.model();
+        // `insertMany` stores documents as given, it can't evaluate expressions
+        if self.options.insert_defaults.contains_key(&model.path().join(".")) {
+            return Err(Error::new(format!("`{}` has computed defaults, create its records one by one", model.name())));
+        }
+        let col = self.get_write_collection(model, "create");
+        let mut docs = Vec::with_capacity(objects.len());
+        for (index, object) in objects.iter().enumerate() {
+            if object.model().path() != model.path() {
+                return Err(Error::new(format!("record {} is not a `{}`, records inserted together must share a model", index, model.name())));
+            }
+            let mut doc = self.object_document(object).await?;
+            // a failed batch doesn't report the ids of the documents it stored
+            if !doc.contains_key("_id") {
+                doc.insert("_id", ObjectId::new());
+            }
+            docs.push(doc);
+        }
+        let ids: Vec<Bson> = docs.iter().map(|doc| doc.get("_id").unwrap().clone()).collect();
+        let options = InsertManyOptions::builder().ordered(ordered).comment(self.comment_bson()).build();
+        let started = Instant::now();
+        let result = match self.session() {
+            Some(session) => col.insert_many_with_session(docs, options, session).await,
+            None => col.insert_many(docs, options).await,
+        };
+        self.report_slow_query("insert", col.name(), started, None);
+        let failures: Vec<(usize, String)> = match &result {
+            Ok(_) => vec![],
+            Err(error) => match error.kind.as_ref() {
+                ErrorKind::BulkWrite(failure) => match Self::batch_failures(failure) {
+                    Some(failures) => failures,
+                    None => return Err(self._handle_write_error(&error.kind, first, path)),
+                },
+                _ => return Err(self._handle_write_error(&error.kind, first, path)),
+            },
+        };
+        let stored = |index: usize| Self::batch_stored(ordered, &failures, index);
+        let mut server_generated = false;
+        for (index, object) in objects.iter().enumerate() {
+            if !stored(index) {
+                continue;
+            }
+            for key in &model.cache().auto_keys {
+                let field = model.field(key).unwrap();
+                if field.column_name() == "_id" {
+                    let new_value = BsonCoder::decode(&self.options, namespace, model, field.r#type(), field.is_optional(), &ids[index], path![]).unwrap();
+                    object.set_value(field.name(), new_value)?;
+                } else {
+                    server_generated = true;
+                }
+            }
+        }
+        // other auto keys are only known to the server
+        if self.options.read_back_created || server_generated {
+            for (index, object) in objects.iter().enumerate() {
+                if stored(index) {
+                    self.read_back(object, &col, doc!{"_id": ids[index].clone()}, path.clone() + index).await?;
+                }
+            }
+        }
+        match Self::batch_error(ordered, &failures, objects.len()) {
+            None => Ok(()),
+            Some((Some(failed), message)) => Err(error_ext::unknown_database_write_error(path + failed, message)),
+            Some((None, message)) => Err(error_ext::unknown_database_write_error(path, message)),
+        }
+    }
+
+    /// The index and message of each record of a batch which failed. `None`
+    /// when only the write concern failed, so every record may be stored.
+    fn batch_failures(failure: &BulkWriteFailure) -> Option<Vec<(usize, String)>> {
+        failure.write_errors.as_ref().map(|write_errors| write_errors.iter().map(|e| (e.index, e.message.clone())).collect())
+    }
+
+    /// Whether record `index` of a batch with `failures` is stored. Ordered
+    /// batches stop at the first failure.
+    fn batch_stored(ordered: bool, failures: &[(usize, String)], index: usize) -> bool {
+        if ordered {
+            failures.first().map_or(true, |(failed, _)| index < *failed)
+        } else {
+            !failures.iter().any(|(failed, _)| *failed == index)
+        }
+    }
+
+    /// The message of a batch of `count` records with `failures`, and the
+    /// index of the failed record of an ordered batch.
+    fn batch_error(ordered: bool, failures: &[(usize, String)], count: usize) -> Option<(Option<usize>, String)> {
+        match failures.first() {
+            None => None,
+            Some((failed, message)) if ordered => Some((Some(*failed), format!(
+                "record {} failed: {}; the {} records before it were stored, the {} after it were not",
+                failed, message, failed, count - failed - 1,
+            ))),
+            Some(_) => Some((None, format!(
+                "{} of {} records failed, the others were stored: {}",
+                failures.len(),
+                count,
+                failures.iter().map(|(failed, message)| format!("record {}: {}", failed, message)).join("; "),
+            ))),
+        }
+    }
+
+    /// Insert `doc` with an upserting pipeline update, so `computed_defaults`,
+    /// aggregation expressions keyed by field name, fill the fields left
+    /// unset from the other stored values. The filter never matches, thus an
+    /// existing `_id` fails with a duplicate key error like an insert does.
+    async fn insert_with_computed_defaults(&self, model: &Model, col: &Collection<Document>, mut doc: Document, computed_defaults: &IndexMap<String, Value>) -> std::result::Result<Bson, MongoDBError> {
+        let id = match doc.remove("_id") {
+            Some(id) => id,
+            None => Bson::ObjectId(ObjectId::new()),
+        };
+        let literals: Document = doc.into_iter().map(|(k, v)| (k, Bson::Document(doc!{"$literal": v}))).collect();
+        let mut computed = doc!{};
+        for (key, expression) in computed_defaults {
+            let column_name = model.field(key).map_or(key.as_str(), |field| field.column_name());
+            computed.insert(column_name, doc!{"$ifNull": [format!("${column_name}"), teon_value_to_bson(expression)?]});
+        }
+        let mut pipeline = vec![];
+        if !literals.is_empty() {
+            pipeline.push(doc!{"$set": literals});
+        }
+        pipeline.push(doc!{"$set": computed});
+        let filter = doc!{"_id": id.clone(), "$expr": {"$eq": [0, 1]}};
+        let options = UpdateOptions::builder().upsert(true).comment(self.comment_bson()).build();
+        match self.session() {
+            Some(session) => col.update_one_with_session(filter, pipeline, options, session).await,
+            None => col.update_one(filter, pipeline, options).await,
+        }.map(|result| result.upserted_id.unwrap_or(id))
+    }
+
+    /// Fail writes the server would reject for exceeding its document size
+    /// limit with an error naming the model, instead of the driver's error.
+    fn check_document_size(model: &Model, document: &Document) -> Result<()> {
+        let size = bson::to_vec(document).map_or(0, |bytes| bytes.len());
+        if size > MAX_DOCUMENT_SIZE {
+            Err(Error::new(format!("record of {} is about {:.1} MB, above MongoDB's 16 MB document limit, consider storing large binaries with GridFS", model.name(), size as f64 / (1024.0 * 1024.0))))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The document key a save key is written to, the column name of a field
+    /// or the key of a property. Every section of a write document uses it.
+    fn save_column<'a>(model: &'a Model, key: &'a str) -> &'a str {
+        Self::column_of(key, model.field(key).map(|field| field.column_name()))
+    }
+
+    /// `key` written to the column of its field, if it names one.
+    fn column_of<'a>(key: &'a str, field_column_name: Option<&'a str>) -> &'a str {
+        field_column_name.unwrap_or(key)
+    }
+
+    /// Raw fields are stored as given, other fields are encoded by type.
+    fn encode_field(&self, model: &Model, field: &Field, value: Value) -> Result<Bson> {
+        if self.options.is_raw_field(model, field.name()) {
+            raw_value_to_bson(&value)
+        } else {
+            BsonCoder::encode(&self.options, field.r#type(), value)
+        }
+    }
+
+    /// Raw fields are read as stored, other fields are decoded by type.
+    fn decode_field(&self, namespace: &Namespace, model: &Model, field: &Field, bson_value: &Bson) -> Result<Value> {
+        if self.options.is_raw_field(model, field.name()) {
+            Ok(raw_bson_to_value(bson_value))
+        } else {
+            BsonCoder::decode(&self.options, namespace, model, field.r#type(), field.is_optional(), bson_value, path![])
+        }
+    }
+
+    /// The value stored for an encoded field value, `None` leaves the field
+    /// unset so it doesn't pass `$exists`: creates skip it and updates
+    /// `$unset` it.
+    fn stored_field_value(empty_array: EmptyArray, value: Bson) -> Option<Bson> {
+        match value {
+            Bson::Null => None,
+            Bson::Array(array) if array.is_empty() => match empty_array {
+                EmptyArray::Store => Some(Bson::Array(array)),
+                EmptyArray::Null => Some(Bson::Null),
+                EmptyArray::Omit => None,
+            },
+            value => Some(value),
+        }
+    }
+
+    /// Insert `column` into a document being written, refusing to overwrite
+    /// the value another field or property already wrote to it.
+    fn insert_unique(document: &mut Document, column: &str, value: Bson, key: &str) -> Result<()> {
+        if document.contains_key(column) {
+            return Err(Error::new(format!("`{}` is written to key `{}` which is already written by another field or property", key, column)));
+        }
+        document.insert(column, value);
+        Ok(())
+    }
+
+    /// Decode the stored document matching `filter` onto `object`, picking up
+    /// values applied by the server.
+    async fn read_back(&self, object: &Object, col: &Collection<Document>, filter: Document, path: KeyPath) -> Result<()> {
+        let result = match self.session() {
+            None => col.find_one(filter, None).await,
+            Some(session) => col.find_one_with_session(filter, None, session).await,
+        };
+        let document = match result {
+            Ok(Some(document)) => document,
+            Ok(None) => return Ok(()),
+            Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+        };
+        let model = object.model();
+        for field in model.fields().values() {
+            if let Some(bson_value) = document.get(field.column_name()) {
+                let value = self.decode_field(object.namespace(), model, field, bson_value)?;
+                object.set_value(field.name(), value)?;
+            }
+        }
+        Ok(())
+    }
+
     async fn update_object(&self, object: &Object, path: KeyPath) -> Result<()> {
         let namespace = object.namespace();
         let model = object.model();
         let keys = object.keys_for_save();
-        let col = self.get_collection(model);
-        let identifier: Bson = teon_value_to_bson(&object.db_identifier());
-        let identifier = identifier.as_document().unwrap();
+        let col = self.get_write_collection(model, "update");
+        let identifier = &self.identifier_filter(object)?;
         let mut set = doc!{};
         let mut unset = doc!{};
         let mut inc = doc!{};
         let mut mul = doc!{};
         let mut push = doc!{};
+        let mut add_to_set = doc!{};
+        let mut pull = doc!{};
+        let mut bit = doc!{};
+        let mut exact: Vec<(&Field, Factor)> = vec![];
         for key in keys {
+            let column_name = Self::save_column(model, key);
             if let Some(field) = model.field(key) {
-                let column_name = field.column_name();
                 if let Some(updator) = object.get_atomic_updator(key) {
+                    if let Err(message) = Self::primary_key_unchanged(&identifier, column_name, key, None) {
+                        return Err(error_ext::unknown_database_write_error(path, message));
+                    }
                     let (key, val) = Input::key_value(updator.as_dictionary().unwrap());
                     match key {
-                        "increment" => inc.insert(column_name, teon_value_to_bson(val)),
-                        "decrement" => inc.insert(column_name, teon_value_to_bson(&val.neg().unwrap())),
-                        "multiply" => mul.insert(column_name, teon_value_to_bson(val)),
-                        "divide" => mul.insert(column_name, Bson::Double(val.recip().unwrap().to_float().unwrap().abs())),
-                        "push" => push.insert(column_name, teon_value_to_bson(val)),
-                        _ => panic!("Unhandled key."),
+                        "increment" => inc.insert(column_name, teon_value_to_bson(val)?),
+                        "decrement" => inc.insert(column_name, teon_value_to_bson(&val.neg()?)?),
+                        "multiply" => match Self::multiplier(field, val)? {
+                            Some(multiplier) => mul.insert(column_name, multiplier),
+                            None => {
+                                exact.push((field, Factor::Times(Self::factor_decimal(field.name(), val)?)));
+                                None
+                            }
+                        },
+                        "divide" => {
+                            let divisor = Self::divisor(field.name(), val)?;
+                            // the reciprocal of most divisors isn't exact
+                            if matches!(field.r#type().unwrap_optional(), FieldType::Int | FieldType::Int64 | FieldType::Decimal) {
+                                exact.push((field, Factor::Over(divisor)));
+                                None
+                            } else {
+                                mul.insert(column_name, Bson::Double(1.0 / divisor.to_f64().unwrap()))
+                            }
+                        }
+                        "push" => push.insert(column_name, Self::array_updator_operand(&self.options, key, Self::element_type(field), val, field.name())?),
+                        "addToSet" => add_to_set.insert(column_name, Self::array_updator_operand(&self.options, key, Self::element_type(field), val, field.name())?),
+                        "pull" => pull.insert(column_name, Self::array_updator_operand(&self.options, key, Self::element_type(field), val, field.name())?),
+                        "bitAnd" | "bitOr" | "bitXor" => bit.insert(column_name, Self::bit_operand(field.r#type(), key, val, field.name())?),
+                        _ => return Err(Error::new(format!("unknown atomic updator `{}` of field `{}`", key, field.name()))),
                     };
                 } else {
-                    let bson_val: Bson = BsonCoder::encode(field.r#type(), object.get_value(&key).unwrap())?;
-                    if bson_val == Bson::Null {
-                        unset.insert(key, bson_val);
-                    } else {
-                        set.insert(key, bson_val);
+                    let bson_val: Bson = self.encode_field(model, field, object.get_value(&key).unwrap())?;
+                    match Self::primary_key_unchanged(&identifier, column_name, key, Some(&bson_val)) {
+                        Ok(true) => continue,
+                        Ok(false) => (),
+                        Err(message) => return Err(error_ext::unknown_database_write_error(path, message)),
+                    }
+                    match Self::stored_field_value(self.options.empty_array, bson_val) {
+                        Some(bson_val) => Self::insert_unique(&mut set, column_name, bson_val, key)?,
+                        None => Self::insert_unique(&mut unset, column_name, Bson::Null, key)?,
                     }
                 }
             } else if let Some(property) = model.property(key) {
-                let bson_val: Bson = BsonCoder::encode(property.r#type(), object.get_property_value(&key).await?)?;
+                let bson_val: Bson = BsonCoder::encode(&self.options, property.r#type(), object.get_property_value(&key).await?)?;
                 if bson_val != Bson::Null {
-                    set.insert(key, bson_val);
+                    Self::insert_unique(&mut set, column_name, bson_val, key)?;
                 } else {
-                    unset.insert(key, bson_val);
+                    Self::insert_unique(&mut unset, column_name, bson_val, key)?;
                 }
             }
         }
@@ -341,32 +947,104 @@ impl MongoDBTransaction {
             update_doc.insert("$push", push);
             return_new = true;
         }
+        if !add_to_set.is_empty() {
+            update_doc.insert("$addToSet", add_to_set);
+            return_new = true;
+        }
+        if !pull.is_empty() {
+            update_doc.insert("$pull", pull);
+            return_new = true;
+        }
+        if !bit.is_empty() {
+            update_doc.insert("$bit", bit);
+            return_new = true;
+        }
+        // exact products are computed from the stored values and only written
+        // while those values are still stored
+        let mut unchanged = doc!{};
+        if !exact.is_empty() {
+            let result = match self.session() {
+                None => col.find_one(identifier.clone(), None).await,
+                Some(session) => col.find_one_with_session(identifier.clone(), None, session).await,
+            };
+            let stored = match result {
+                Ok(Some(stored)) => stored,
+                Ok(None) => return Err(error_ext::unknown_database_write_error(path, "record to update is not found")),
+                Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            };
+            let mut products = doc!{};
+            for (field, factor) in &exact {
+                let column_name = field.column_name();
+                let current = stored.get(column_name).cloned().unwrap_or(Bson::Null);
+                products.insert(column_name, Self::exact_product(field.r#type().unwrap_optional(), field.name(), &current, factor, self.operation.fractional_multiplier)?);
+                unchanged.insert(column_name, current);
+            }
+            match update_doc.get_document_mut("$set") {
+                Ok(set) => set.extend(products),
+                Err(_) => { update_doc.insert("$set", products); }
+            }
+            return_new = true;
+        }
         if update_doc.is_empty() {
-            return Ok(());
+            match &self.options.empty_update {
+                EmptyUpdate::Skip => return Ok(()),
+                EmptyUpdate::Touch(field_name) => {
+                    let Some(field) = model.field(field_name) else {
+                        return Ok(());
+                    };
+                    let (set, now) = Self::touch_update(&self.options, field.r#type(), field.column_name())?;
+                    update_doc.insert("$set", set);
+                    object.set_value(field_name, now)?;
+                }
+            }
+        }
+        Self::check_document_size(model, &update_doc)?;
+        let mut filter = match &self.operation.update_guard {
+            Some(guard) => doc!{"$and": [identifier.clone(), Aggregation::build_scalar_where(&self.options, namespace, model, guard)?]},
+            None => identifier.clone(),
+        };
+        if !unchanged.is_empty() {
+            filter = doc!{"$and": [filter, unchanged]};
         }
         if !return_new {
+            let options = UpdateOptions::builder().comment(self.comment_bson()).build();
+            let started = Instant::now();
             let result = match self.session() {
-                None => col.update_one(identifier.clone(), update_doc, None).await,
-                Some(session) => col.update_one_with_session(identifier.clone(), update_doc, None, session).await,
+                None => col.update_one(filter, update_doc, options).await,
+                Some(session) => col.update_one_with_session(filter, update_doc, options, session).await,
             };
+            self.report_slow_query("update", col.name(), started, None);
             return match result {
-                Ok(_) => Ok(()),
+                Ok(result) => if result.matched_count == 0 && self.operation.update_guard.is_some() {
+                    Err(self.guard_failure(&col, identifier, path).await)
+                } else {
+                    Ok(())
+                },
                 Err(error) => {
                     Err(self._handle_write_error(&error.kind, object, path))
                 }
             }
         } else {
-            let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+            let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).comment(self.comment_bson()).build();
+            let started = Instant::now();
             let result = match self.session() {
-                None => col.find_one_and_update(identifier.clone(), update_doc, options).await,
-                Some(session) => col.find_one_and_update_with_session(identifier.clone(), update_doc, options, session).await,
+                None => col.find_one_and_update(filter, update_doc, options).await,
+                Some(session) => col.find_one_and_update_with_session(filter, update_doc, options, session).await,
             };
+            self.report_slow_query("update", col.name(), started, None);
             match result {
                 Ok(updated_document) => {
+                    if updated_document.is_none() && !exact.is_empty() {
+                        let names = exact.iter().map(|(field, _)| format!("`{}`", field.name())).join(", ");
+                        return Err(error_ext::unknown_database_write_error(path, format!("{} changed while the update was computed, or the update precondition failed", names)));
+                    }
+                    if updated_document.is_none() && self.operation.update_guard.is_some() {
+                        return Err(self.guard_failure(&col, identifier, path).await);
+                    }
                     for (key, value) in object.inner.atomic_updater_map.lock().unwrap().iter() {
-                        let bson_new_val = updated_document.as_ref().unwrap().get(key).unwrap();
+                        let bson_new_val = updated_document.as_ref().unwrap().get(Self::save_column(model, key)).unwrap();
                         let field = object.model().field(key).unwrap();
-                        let field_value = BsonCoder::decode(namespace, model, field.r#type(), field.is_optional(), bson_new_val, path![])?;
+                        let field_value = self.decode_field(namespace, model, field, bson_new_val)?;
                         object.set_value(key, field_value).unwrap();
                     }
                 }
@@ -378,90 +1056,962 @@ impl MongoDBTransaction {
         Ok(())
     }
 
-}
+    /// The type of the elements of array `field`, which values added to or
+    /// removed from it are encoded as.
+    fn element_type(field: &Field) -> &FieldType {
+        match field.r#type().unwrap_optional() {
+            FieldType::Array(inner) => inner.unwrap_optional(),
+            r#type => r#type,
+        }
+    }
 
-#[async_trait]
-impl Transaction for MongoDBTransaction {
+    /// The `$push`, `$addToSet` or `$pull` operand of `val` for an array of
+    /// `element_type`. An array of elements adds or removes each of them.
+    fn array_updator_operand(options: &ConnectionOptions, key: &str, element_type: &FieldType, val: &Value, name: &str) -> Result<Bson> {
+        let each = match val {
+            Value::Array(values) if !matches!(element_type, FieldType::Array(_)) => Some(values),
+            _ => None,
+        };
+        match (key, each) {
+            // matched against stored elements like `$in` values
+            ("pull", Some(_)) => Ok(Bson::Document(doc!{"$in": BsonCoder::encode_filter_value(options, element_type, val)?})),
+            ("pull", None) => BsonCoder::encode_filter_value(options, element_type, val),
+            (_, Some(values)) => Ok(Bson::Document(doc!{"$each": values.iter().map(|v| BsonCoder::encode_checked(options, element_type, v.clone(), name)).collect::<Result<Vec<Bson>>>()?})),
+            (_, None) => BsonCoder::encode_checked(options, element_type, val.clone(), name),
+        }
+    }
 
-    async fn migrate(&self, models: Vec<&Model>, dry_run: bool, reset_database: bool, silent: bool) -> Result<()> {
-        if reset_database {
-            let _ = self.database.drop(None).await;
+    /// The `$mul` operand for `field`, or `None` when the product must be
+    /// computed exactly instead. Integer fields multiplied by a double would
+    /// be stored as doubles, so integral multipliers are sent as integers and
+    /// fractional ones need the exact product.
+    fn multiplier(field: &Field, multiplier: &Value) -> Result<Option<Bson>> {
+        if !matches!(field.r#type().unwrap_optional(), FieldType::Int | FieldType::Int64) {
+            return Ok(Some(teon_value_to_bson(multiplier)?));
         }
-        for model in models {
-            let _name = model.name();
-            let collection = self.get_collection(model);
-            let mut reviewed_names: Vec<String> = Vec::new();
-            let cursor_result = collection.list_indexes(None).await;
-            if cursor_result.is_ok() {
-                let mut cursor = cursor_result.unwrap();
-                while let Some(Ok(index)) = cursor.next().await {
-                    if index.keys == doc!{"_id": 1} {
-                        continue
-                    }
-                    let name = (&index).options.as_ref().unwrap().name.as_ref().unwrap();
-                    let result = model.indexes().values().find(|i| name == i.name());
-                    if result.is_none() {
-                        // not in our model definition, but in the database
-                        // drop this index
-                        let _ = collection.drop_index(name, None).await.unwrap();
-                    } else {
-                        let result = result.unwrap();
-                        let our_format_index: Index = Index::from_index_model(&index);
-                        if result != &our_format_index {
-                            // alter this index
-                            // drop first
-                            let _ = collection.drop_index(name, None).await.unwrap();
-                            // create index
-                            let index_options = IndexOptions::builder()
-                                .name(result.name().to_string())
-                                .unique(result.r#type() == Type::Unique || result.r#type() == Type::Primary)
-                                .sparse(true)
-                                .build();
-                            let mut keys = doc!{};
-                            for item in result.items() {
-                                let field = model.field(&item.field).unwrap();
-                                let column_name = field.column_name();
-                                keys.insert(column_name, if item.sort == Sort::Asc { 1 } else { -1 });
-                            }
-                            let index_model = IndexModel::builder().keys(keys).options(index_options).build();
-                            let _result = collection.create_index(index_model, None).await;
-                        }
-                    }
-                    reviewed_names.push(name.clone());
+        match multiplier {
+            Value::Int(_) | Value::Int64(_) => Ok(Some(teon_value_to_bson(multiplier)?)),
+            _ => match multiplier.to_float() {
+                Some(f) if f.fract() == 0.0 && f.abs() <= i64::MAX as f64 => Ok(Some(Bson::Int64(f as i64))),
+                _ => Ok(None),
+            },
+        }
+    }
+
+    /// `factor` of an exactly computed update of `field_name`.
+    fn factor_decimal(field_name: &str, factor: &Value) -> Result<BigDecimal> {
+        let decimal = match factor {
+            Value::Int(n) => Some(BigDecimal::from(*n)),
+            Value::Int64(n) => Some(BigDecimal::from(*n)),
+            Value::Float32(f) => BigDecimal::from_f32(*f),
+            Value::Float(f) => BigDecimal::from_f64(*f),
+            Value::Decimal(d) => Some(d.clone()),
+            _ => None,
+        };
+        decimal.ok_or_else(|| Error::new(format!("cannot update `{}` by {:?}", field_name, factor)))
+    }
+
+    /// The divisor of a `divide` update of `field_name`, keeping its sign.
+    fn divisor(field_name: &str, divisor: &Value) -> Result<BigDecimal> {
+        let divisor = Self::factor_decimal(field_name, divisor)?;
+        if divisor.is_zero() {
+            return Err(Error::new(format!("cannot divide `{}` by zero", field_name)));
+        }
+        Ok(divisor)
+    }
+
+    /// The value `factor` turns `stored` into, computed exactly. Integer
+    /// fields fail or round fractional products as `fractional_multiplier`
+    /// tells, decimal fields keep the 34 digits `Decimal128` stores.
+    fn exact_product(field_type: &FieldType, field_name: &str, stored: &Bson, factor: &Factor, fractional_multiplier: FractionalMultiplier) -> Result<Bson> {
+        let value = match stored {
+            // like `$mul`, missing values count as zero
+            Bson::Null => Some(BigDecimal::zero()),
+            Bson::Int32(n) => Some(BigDecimal::from(*n)),
+            Bson::Int64(n) => Some(BigDecimal::from(*n)),
+            Bson::Double(f) => BigDecimal::from_f64(*f),
+            Bson::Decimal128(d) => BigDecimal::from_str(&d.to_string()).ok(),
+            _ => None,
+        };
+        let Some(value) = value else {
+            return Err(Error::new(format!("`{}` doesn't store a number", field_name)));
+        };
+        let product = match factor {
+            Factor::Times(multiplier) => value * multiplier,
+            Factor::Over(divisor) => value / divisor,
+        };
+        match field_type {
+            FieldType::Int | FieldType::Int64 => {
+                let product = if product.is_integer() {
+                    product
+                } else if fractional_multiplier == FractionalMultiplier::Round {
+                    Self::round_half_away_from_zero(&product)
+                } else {
+                    return Err(Error::new(format!("updating integer field `{}` would store the fraction {}", field_name, product)));
+                };
+                let stored = if matches!(field_type, FieldType::Int) {
+                    product.to_i32().map(Bson::Int32)
+                } else {
+                    product.to_i64().map(Bson::Int64)
+                };
+                stored.ok_or_else(|| Error::new(format!("`{}` can't store {}", field_name, product)))
+            }
+            _ => decimal_to_bson(&product.with_prec(34)).ok_or_else(|| Error::new(format!("`{}` can't store {}", field_name, product))),
+        }
+    }
+
+    fn round_half_away_from_zero(decimal: &BigDecimal) -> BigDecimal {
+        let truncated = decimal.with_scale(0);
+        if (decimal - &truncated).abs() * BigDecimal::from(2) >= BigDecimal::one() {
+            truncated + BigDecimal::from(if decimal.is_negative() { -1 } else { 1 })
+        } else {
+            truncated
+        }
+    }
+
+    /// The filter matching the stored record of `object` by every column of
+    /// its primary index, so records keyed by a composite natural key are
+    /// never matched by part of it.
+    fn identifier_filter(&self, object: &Object) -> Result<Document> {
+        let model = object.model();
+        let identifier = object.db_identifier();
+        let mut filter = doc!{};
+        for key in model.primary_index().unwrap().keys() {
+            let field = model.field(key).unwrap();
+            let column_name = field.column_name();
+            let Some(value) = identifier.get(column_name) else {
+                return Err(Error::new(format!("identifier of model `{}` lacks primary key `{}`", model.name(), column_name)));
+            };
+            filter.insert(column_name, BsonCoder::encode(&self.options, field.r#type(), value.clone())?);
+        }
+        Ok(filter)
+    }
+
+    /// Tell a guarded update which didn't match because of its guard apart
+    /// from one whose record is gone.
+    async fn guard_failure(&self, col: &Collection<Document>, identifier: &Document, path: KeyPath) -> Error {
+        let result = match self.session() {
+            None => col.count_documents(identifier.clone(), None).await,
+            Some(session) => col.count_documents_with_session(identifier.clone(), None, session).await,
+        };
+        match result {
+            Ok(0) => error_ext::unknown_database_write_error(path, "record to update is not found"),
+            Ok(_) => error_ext::unknown_database_write_error(path, "update precondition failed"),
+            Err(err) => error_ext::unknown_database_write_error(path, format!("{}", err)),
+        }
+    }
+
+    /// Atomically decrement `field_name` of the saved `object` by `amount`,
+    /// failing with an "insufficient" error and leaving the record untouched
+    /// when the stored value is less than `amount`. The object receives the
+    /// decremented value.
+    pub async fn decrement_clamped(&self, object: &Object, field_name: &str, amount: &Value, path: KeyPath) -> Result<()> {
+        let namespace = object.namespace();
+        let model = object.model();
+        let Some(field) = model.field(field_name) else {
+            return Err(Error::new(format!("field `{}` is not defined on model `{}`", field_name, model.name())));
+        };
+        if !matches!(field.r#type().unwrap_optional(), FieldType::Int | FieldType::Int64 | FieldType::Float32 | FieldType::Float) {
+            return Err(Error::new(format!("clamped decrement requires a numeric field, but `{}` is not", field.name())));
+        }
+        let column_name = field.column_name();
+        let col = self.get_write_collection(model, "update");
+        let identifier = &self.identifier_filter(object)?;
+        let (condition, update) = Self::clamped_decrement(&self.options, field.r#type(), column_name, field_name, amount)?;
+        let filter = doc!{"$and": [identifier.clone(), condition]};
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).comment(self.comment_bson()).build();
+        let started = Instant::now();
+        let result = match self.session() {
+            None => col.find_one_and_update(filter, update, options).await,
+            Some(session) => col.find_one_and_update_with_session(filter, update, options, session).await,
+        };
+        self.report_slow_query("update", col.name(), started, None);
+        let updated_document = match result {
+            Ok(Some(document)) => document,
+            Ok(None) => return Err(self.insufficient_failure(&col, identifier, field_name, path).await),
+            Err(error) => return Err(self._handle_write_error(&error.kind, object, path)),
+        };
+        let Some(bson_new_val) = updated_document.get(column_name) else {
+            return Err(error_ext::record_decoding_error(model.name(), path, "number"));
+        };
+        let value = BsonCoder::decode(&self.options, namespace, model, field.r#type(), field.is_optional(), bson_new_val, path![field_name])?;
+        object.set_value(field_name, value)
+    }
+
+    /// The condition requiring at least `amount` in `column_name` and the
+    /// update taking it away. `amount` must be a positive number of the
+    /// field's type.
+    fn clamped_decrement(options: &ConnectionOptions, r#type: &FieldType, column_name: &str, field_name: &str, amount: &Value) -> Result<(Document, Document)> {
+        let amount = BsonCoder::encode_checked(options, r#type, amount.clone(), field_name)?;
+        let negated = match amount {
+            Bson::Int32(n) if n > 0 => Bson::Int32(-n),
+            Bson::Int64(n) if n > 0 => Bson::Int64(-n),
+            Bson::Double(n) if n > 0.0 && n.is_finite() => Bson::Double(-n),
+            _ => return Err(Error::new(format!("clamped decrement of `{}` requires a positive amount", field_name))),
+        };
+        Ok((doc!{column_name: {"$gte": amount}}, doc!{"$inc": {column_name: negated}}))
+    }
+
+    /// Tell a clamped decrement which didn't match because the value is too
+    /// low apart from one whose record is gone.
+    async fn insufficient_failure(&self, col: &Collection<Document>, identifier: &Document, field_name: &str, path: KeyPath) -> Error {
+        let result = match self.session() {
+            None => col.count_documents(identifier.clone(), None).await,
+            Some(session) => col.count_documents_with_session(identifier.clone(), None, session).await,
+        };
+        match result {
+            Ok(0) => error_ext::unknown_database_write_error(path, "record to update is not found"),
+            Ok(_) => error_ext::unknown_database_write_error(path, format!("insufficient `{}`", field_name)),
+            Err(err) => error_ext::unknown_database_write_error(path, format!("{}", err)),
+        }
+    }
+
+    /// Migrate `model`, listing the index builds in progress in `pending` so
+    /// a migration past its deadline can abort them.
+    async fn migrate_model(&self, model: &Model, pending: &Mutex<Vec<(String, String)>>) -> Result<()> {
+        let collection = self.get_collection(model);
+        let mut errors: Vec<String> = vec![];
+        if self.options.change_stream_pre_and_post_images {
+            let mut result = self.database.run_command(doc!{"collMod": collection.name(), "changeStreamPreAndPostImages": {"enabled": true}}, None).await.map(|_| ());
+            if matches!(&result, Err(err) if matches!(*err.kind, ErrorKind::Command(ref command_error) if command_error.code == NAMESPACE_NOT_FOUND)) {
+                let options = CreateCollectionOptions::builder()
+                    .collation(self.options.default_collation.clone())
+                    .change_stream_pre_and_post_images(ChangeStreamPreAndPostImages::builder().enabled(true).build())
+                    .build();
+                result = self.database.create_collection(collection.name(), options).await;
+            }
+            if let Err(err) = result {
+                errors.push(format!("pre-images of {} enable error: {}", model.name(), err));
+            }
+        }
+        if let Some(collation) = &self.options.default_collation {
+            // collections only take a default collation when created
+            let options = CreateCollectionOptions::builder().collation(collation.clone()).build();
+            if let Err(err) = self.database.create_collection(collection.name(), options).await {
+                if !matches!(*err.kind, ErrorKind::Command(ref command_error) if command_error.code == NAMESPACE_EXISTS) {
+                    errors.push(format!("collection of {} create error: {}", model.name(), err));
                 }
             }
-            for (_, index) in model.indexes() {
-                if !reviewed_names.contains_str(index.name()) {
-                    // ignore primary
-                    if index.keys().len() == 1 {
-                        let field = model.field(index.keys().get(0).unwrap()).unwrap();
-                        if field.column_name() == "_id" {
-                            continue
+        }
+        let mut reviewed_names: Vec<String> = Vec::new();
+        let cursor_result = collection.list_indexes(None).await;
+        if cursor_result.is_ok() {
+            let mut cursor = cursor_result.unwrap();
+            while let Some(Ok(index)) = cursor.next().await {
+                if index.keys == doc!{"_id": 1} {
+                    continue
+                }
+                let name = (&index).options.as_ref().unwrap().name.as_ref().unwrap();
+                let result = model.indexes().values().find(|i| name == i.name());
+                if result.is_none() {
+                    // not in our model definition, but in the database
+                    // drop this index
+                    if let Err(err) = collection.drop_index(name, None).await {
+                        errors.push(format!("index {} of {} drop error: {}", name, model.name(), err));
+                        if self.options.index_drop_failure == IndexDropFailure::Abort {
+                            break;
                         }
                     }
-                    // create this index
-                    let index_options = IndexOptions::builder()
-                        .name(index.name().to_string())
-                        .unique(index.r#type() == Type::Unique || index.r#type() == Type::Primary)
-                        .sparse(true)
-                        .build();
-                    let mut keys = doc!{};
-                    for item in index.items() {
-                        let field = model.field(&item.field).unwrap();
-                        let column_name = field.column_name();
-                        keys.insert(column_name, if item.sort == Sort::Asc { 1 } else { -1 });
+                } else {
+                    let result = result.unwrap();
+                    let our_format_index: Index = Index::from_index_model(&index);
+                    if result != &our_format_index || UniqueNulls::from_index_model(&index) != self.options.unique_nulls(model, result) {
+                        // alter this index
+                        // drop first
+                        if let Err(err) = collection.drop_index(name, None).await {
+                            errors.push(format!("index {} of {} drop error: {}", name, model.name(), err));
+                            if self.options.index_drop_failure == IndexDropFailure::Abort {
+                                break;
+                            }
+                            reviewed_names.push(name.clone());
+                            continue;
+                        }
+                        // create index
+                        if let Err(err) = Self::create_index_tracked(&collection, self.index_model(model, result), pending).await {
+                            errors.push(format!("index {} of {} create error: {}", result.name(), model.name(), err));
+                        }
                     }
-                    let index_model = IndexModel::builder().keys(keys).options(index_options).build();
-                    let result = collection.create_index(index_model, None).await;
-                    if result.is_err() {
-                        println!("index create error: {:?}", result.err().unwrap());
+                }
+                reviewed_names.push(name.clone());
+            }
+        }
+        if !errors.is_empty() && self.options.index_drop_failure == IndexDropFailure::Abort {
+            return Err(Error::new(errors.join("\n")));
+        }
+        for (_, index) in model.indexes() {
+            if !reviewed_names.contains_str(index.name()) {
+                // ignore primary
+                if index.keys().len() == 1 {
+                    let field = model.field(index.keys().get(0).unwrap()).unwrap();
+                    if field.column_name() == "_id" {
+                        continue
                     }
                 }
+                // create this index
+                if let Err(err) = Self::create_index_tracked(&collection, self.index_model(model, index), pending).await {
+                    errors.push(format!("index {} of {} create error: {}", index.name(), model.name(), err));
+                }
             }
         }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(errors.join("\n")))
+        }
+    }
+
+    /// Create `index_model`, listed in `pending` until the build finishes.
+    /// Builds abandoned by a migration past its deadline stay listed.
+    async fn create_index_tracked(collection: &Collection<Document>, index_model: IndexModel, pending: &Mutex<Vec<(String, String)>>) -> std::result::Result<(), MongoDBError> {
+        let build = (collection.name().to_owned(), index_model.options.as_ref().and_then(|o| o.name.clone()).unwrap_or_default());
+        pending.lock().unwrap().push(build.clone());
+        let result = collection.create_index(index_model, None).await;
+        pending.lock().unwrap().retain(|pending_build| pending_build != &build);
+        result.map(|_| ())
+    }
+
+    /// The error of a migration which exceeded `deadline`.
+    fn migration_deadline_report(deadline: Duration, models: &[&str], completed: &[&str], failed: &[&str], aborted: &[String], not_aborted: &[String]) -> String {
+        let unfinished = models.iter().filter(|name| !completed.contains(*name) && !failed.contains(*name)).join(", ");
+        let mut report = format!("migration exceeded its deadline of {:?}, completed: [{}], failed: [{}], unfinished: [{}]", deadline, completed.join(", "), failed.join(", "), unfinished);
+        if !aborted.is_empty() {
+            report += &format!(", aborted index builds: [{}]", aborted.join(", "));
+        }
+        if !not_aborted.is_empty() {
+            report += &format!(", index builds which may still run: [{}]", not_aborted.join(", "));
+        }
+        report
+    }
+
+    fn index_model(&self, model: &Model, index: &Index) -> IndexModel {
+        let mut keys = doc!{};
+        let mut exists = doc!{};
+        for item in index.items() {
+            let field = model.field(&item.field).unwrap();
+            let column_name = field.column_name();
+            keys.insert(column_name, if item.sort == Sort::Asc { 1 } else { -1 });
+            exists.insert(column_name, doc!{"$exists": true});
+        }
+        let unique_nulls = self.options.unique_nulls(model, index);
+        let index_options = IndexOptions::builder()
+            .name(index.name().to_string())
+            .unique(index.r#type() == Type::Unique || index.r#type() == Type::Primary)
+            .sparse(if unique_nulls == UniqueNulls::Sparse { Some(true) } else { None })
+            .partial_filter_expression(if unique_nulls == UniqueNulls::PartialExists { Some(exists) } else { None })
+            .collation(self.options.default_collation.clone())
+            .build();
+        IndexModel::builder().keys(keys).options(index_options).build()
+    }
+
+    /// Sample up to `sample_size` documents and report, per field, how many of
+    /// them hold a value which doesn't decode as the declared type. Decoding
+    /// failures are counted rather than returned.
+    pub async fn validate_schema(&self, namespace: &Namespace, model: &Model, sample_size: i64, path: KeyPath) -> Result<Value> {
+        let col = self.get_collection(model);
+        let mut documents = vec![];
+        for result in self.aggregate_to_documents(vec![doc!{"$sample": {"size": sample_size}}], col, path.clone()).await? {
+            match result {
+                Ok(document) => documents.push(document),
+                Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            }
+        }
+        let fields: Vec<_> = model.fields().values().collect();
+        let columns: Vec<(&str, &str)> = fields.iter().map(|field| (field.name(), field.column_name())).collect();
+        Ok(Self::schema_drift_report(&documents, &columns, |index, bson_value| {
+            let field = fields[index];
+            BsonCoder::decode(&self.options, namespace, model, field.r#type(), field.is_optional(), bson_value, path![]).is_ok()
+        }))
+    }
+
+    /// Counts, per `(name, column)`, the documents whose value `decodes`
+    /// rejects, given the column's index. Missing columns aren't counted.
+    fn schema_drift_report(documents: &[Document], columns: &[(&str, &str)], decodes: impl Fn(usize, &Bson) -> bool) -> Value {
+        let mut mismatches = teon!({});
+        for document in documents {
+            for (index, &(name, column_name)) in columns.iter().enumerate() {
+                let Some(bson_value) = document.get(column_name) else {
+                    continue
+                };
+                if !decodes(index, bson_value) {
+                    let count = mismatches.get(name).map_or(0, |c| c.as_int64().unwrap());
+                    mismatches.as_dictionary_mut().unwrap().insert(name.to_string(), Value::Int64(count + 1));
+                }
+            }
+        }
+        teon!({
+            "sampled": documents.len() as i64,
+            "mismatches": mismatches,
+        })
+    }
+
+    /// Run the finder on `model` and append the documents of other collections
+    /// with `$unionWith`. Each union is either a collection name or a
+    /// `{ "coll": name, "pipeline": [...] }` dictionary. As the collections
+    /// may differ in shape, rows are decoded leniently into values.
+    pub async fn union_with(&self, namespace: &Namespace, model: &Model, finder: &Value, unions: &Value, path: KeyPath) -> Result<Vec<Value>> {
+        let Some(unions) = unions.as_array() else {
+            return Err(Error::new("`unionWith` takes an array"));
+        };
+        self.require_server_version(4, 4, "$unionWith").await?;
+        let mut aggregate_input = Aggregation::build(&self.options, namespace, model, finder)?;
+        for union in unions {
+            if let Some(coll) = union.as_str() {
+                aggregate_input.push(doc!{"$unionWith": coll});
+            } else {
+                aggregate_input.push(doc!{"$unionWith": teon_value_to_bson(union)?});
+            }
+        }
+        let col = self.get_collection(model);
+        let results = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
+        Self::documents_to_values(results, path)
+    }
+
+    /// Convert the values of `field_name` which aren't stored as the declared
+    /// type, e.g. strings in an `Int` field. With `dry_run`, only count them.
+    /// Values which can't be converted are left as they are, converted ones
+    /// are kept in a backup field until `restore_field_type` puts them back.
+    /// Returns `{ "matched": n, "modified": n, "unconvertible": n }`.
+    pub async fn repair_field_type(&self, model: &Model, field_name: &str, dry_run: bool, path: KeyPath) -> Result<Value> {
+        let Some(field) = model.field(field_name) else {
+            return Err(Error::new(format!("field `{}` is not defined on model `{}`", field_name, model.name())));
+        };
+        let Some(bson_type) = Self::repair_bson_type(field.r#type()) else {
+            return Err(Error::new(format!("cannot repair field `{}` of this type", field_name)));
+        };
+        let column_name = field.column_name();
+        let filter = doc!{column_name: {"$exists": true, "$ne": null, "$not": {"$type": bson_type}}};
+        let col = self.get_collection(model);
+        if dry_run {
+            let result = match self.session() {
+                None => col.count_documents(filter, None).await,
+                Some(session) => col.count_documents_with_session(filter, None, session).await,
+            };
+            return match result {
+                Ok(count) => Ok(teon!({"matched": count as i64, "modified": 0i64, "unconvertible": 0i64})),
+                Err(err) => Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            };
+        }
+        let pipeline = Self::repair_pipeline(column_name, bson_type);
+        let result = match self.session() {
+            None => col.update_many(filter, pipeline, None).await,
+            Some(session) => col.update_many_with_session(filter, pipeline, None, session).await,
+        };
+        match result {
+            // every converted value changes type, the others are left unmodified
+            Ok(result) => Ok(teon!({
+                "matched": result.matched_count as i64,
+                "modified": result.modified_count as i64,
+                "unconvertible": (result.matched_count - result.modified_count) as i64,
+            })),
+            Err(err) => Err(error_ext::unknown_database_write_error(path, format!("{}", err))),
+        }
+    }
+
+    /// Put back the values of `field_name` converted by `repair_field_type`.
+    /// Returns `{ "restored": n }`.
+    pub async fn restore_field_type(&self, model: &Model, field_name: &str, path: KeyPath) -> Result<Value> {
+        let Some(field) = model.field(field_name) else {
+            return Err(Error::new(format!("field `{}` is not defined on model `{}`", field_name, model.name())));
+        };
+        let column_name = field.column_name();
+        let backup = Self::repair_backup_column(column_name);
+        let filter = doc!{&backup: {"$exists": true}};
+        let pipeline = vec![doc!{"$set": {column_name: format!("${backup}")}}, doc!{"$unset": &backup}];
+        let col = self.get_collection(model);
+        let result = match self.session() {
+            None => col.update_many(filter, pipeline, None).await,
+            Some(session) => col.update_many_with_session(filter, pipeline, None, session).await,
+        };
+        match result {
+            Ok(result) => Ok(teon!({"restored": result.modified_count as i64})),
+            Err(err) => Err(error_ext::unknown_database_write_error(path, format!("{}", err))),
+        }
+    }
+
+    /// The `$type` alias of the values `repair_field_type` converts to.
+    fn repair_bson_type(r#type: &FieldType) -> Option<&'static str> {
+        Some(match r#type.unwrap_optional() {
+            FieldType::Int => "int",
+            FieldType::Int64 => "long",
+            FieldType::Float32 | FieldType::Float => "double",
+            FieldType::Decimal => "decimal",
+            FieldType::Bool => "bool",
+            FieldType::String => "string",
+            FieldType::ObjectId => "objectId",
+            FieldType::Date | FieldType::DateTime => "date",
+            _ => return None,
+        })
+    }
+
+    /// Where `repair_field_type` keeps the original values of `column_name`.
+    fn repair_backup_column(column_name: &str) -> String {
+        format!("__before_repair_{column_name}")
+    }
+
+    /// The update pipeline converting `column_name` to `bson_type`. Values
+    /// which fail to convert are kept, the originals of converted ones are
+    /// backed up.
+    fn repair_pipeline(column_name: &str, bson_type: &str) -> Vec<Document> {
+        let input = format!("${column_name}");
+        let converted = if bson_type == "bool" {
+            // `$convert` makes every string, even "false", true
+            doc!{"$switch": {"branches": [
+                {"case": {"$in": [&input, ["true", 1]]}, "then": true},
+                {"case": {"$in": [&input, ["false", 0]]}, "then": false},
+            ], "default": null}}
+        } else {
+            doc!{"$convert": {"input": &input, "to": bson_type, "onError": null, "onNull": null}}
+        };
+        let temporary = format!("__repairing_{column_name}");
+        let backup = Self::repair_backup_column(column_name);
+        let failed = doc!{"$eq": [format!("${temporary}"), null]};
+        vec![
+            doc!{"$set": {&temporary: converted}},
+            doc!{"$set": {
+                column_name: {"$cond": [&failed, &input, format!("${temporary}")]},
+                backup: {"$cond": [&failed, "$$REMOVE", &input]},
+            }},
+            doc!{"$unset": temporary},
+        ]
+    }
+
+    /// Run the finder on `model` and fill the gaps of the datetime `field_name`
+    /// with `$densify`, one document per `step` `unit`s. `fill` is the `output`
+    /// of a `$fill` stage, e.g. `{ "count": { "value": 0 } }`, sorted by the
+    /// densified field. Generated rows lack most fields, so rows are decoded
+    /// leniently into values.
+    pub async fn densify(&self, namespace: &Namespace, model: &Model, finder: &Value, field_name: &str, step: i64, unit: &str, fill: Option<&Value>, path: KeyPath) -> Result<Vec<Value>> {
+        if fill.is_some() {
+            self.require_server_version(5, 3, "$fill").await?;
+        } else {
+            self.require_server_version(5, 1, "$densify").await?;
+        }
+        let Some(field) = model.field(field_name) else {
+            return Err(Error::new(format!("field `{}` is not defined on model `{}`", field_name, model.name())));
+        };
+        let column_name = field.column_name();
+        let mut aggregate_input = Aggregation::build(&self.options, namespace, model, finder)?;
+        aggregate_input.push(doc!{"$densify": {"field": column_name, "range": {"step": step, "unit": unit, "bounds": "full"}}});
+        if let Some(fill) = fill {
+            aggregate_input.push(doc!{"$fill": {"sortBy": {column_name: 1}, "output": teon_value_to_bson(fill)?}});
+        }
+        let col = self.get_collection(model);
+        let results = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
+        Self::documents_to_values(results, path)
+    }
+
+    /// Count the records of `model` matching `filter`, a `where` input, per
+    /// value of `field_name` with `$sortByCount`. Rows are `{ "_id": value,
+    /// "count": n }`, most frequent value first.
+    pub async fn sort_by_count(&self, namespace: &Namespace, model: &Model, field_name: &str, filter: Option<&Value>, path: KeyPath) -> Result<Vec<Value>> {
+        let Some(field) = model.field(field_name) else {
+            return Err(Error::new(format!("field `{}` is not defined on model `{}`", field_name, model.name())));
+        };
+        let mut aggregate_input = vec![];
+        if let Some(filter) = filter {
+            // relation keys need lookups this pipeline doesn't have
+            let r#match = Aggregation::build_scalar_where(&self.options, namespace, model, filter)?;
+            if !r#match.is_empty() {
+                aggregate_input.push(doc!{"$match": r#match});
+            }
+        }
+        aggregate_input.push(doc!{"$sortByCount": format!("${}", field.column_name())});
+        let col = self.get_collection(model);
+        let mut retval = vec![];
+        for result in self.aggregate_to_documents(aggregate_input, col, path.clone()).await? {
+            let document = match result {
+                Ok(document) => document,
+                Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            };
+            let value = match document.get("_id") {
+                Some(bson) => self.decode_group_key(namespace, model, field, bson)?,
+                None => Value::Null,
+            };
+            let count = match document.get("count") {
+                Some(count) => count.as_i32().map(|c| c as i64).or(count.as_i64()).unwrap_or(0),
+                None => 0,
+            };
+            retval.push(teon!({"_id": value, "count": count}));
+        }
+        Ok(retval)
+    }
+
+    /// Per index usage of the collection of `model` from `$indexStats`, e.g.
+    /// `{ "name": "_id_", "accesses": { "ops": 12, "since": ... } }`, to find
+    /// indexes worth dropping. Counters reset when the server restarts.
+    pub async fn index_stats(&self, model: &Model, path: KeyPath) -> Result<Vec<Value>> {
+        let col = self.get_collection(model);
+        let results = self.aggregate_to_documents(vec![doc!{"$indexStats": {}}], col, path.clone()).await?;
+        Self::documents_to_values(results, path)
+    }
+
+    /// Run the finder on `model` and reshape the documents with the `$project`
+    /// `projection`, e.g. `{ "name": 1, "author": { "id": "$authorId" } }`.
+    /// Rows are decoded leniently into values without building objects, for
+    /// lightweight read paths.
+    pub async fn find_values(&self, namespace: &Namespace, model: &Model, finder: &Value, projection: &Value, path: KeyPath) -> Result<Vec<Value>> {
+        let Bson::Document(projection) = teon_value_to_bson(projection)? else {
+            return Err(Error::new("projection must be a dictionary"));
+        };
+        let mut aggregate_input = Aggregation::build(&self.options, namespace, model, finder)?;
+        aggregate_input.push(doc!{"$project": projection});
+        let col = self.get_collection(model);
+        let results = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
+        Self::documents_to_values(results, path)
+    }
+
+    /// Run the finder on `model` and flatten the array field `field_name`
+    /// with `$unwind`, one row per element. Records whose array is missing or
+    /// empty are kept with the field absent when `preserve_null_and_empty_arrays`
+    /// is set. `include_array_index` names the output key of each element's
+    /// index. Rows are decoded leniently into values.
+    pub async fn unwind(&self, namespace: &Namespace, model: &Model, finder: &Value, field_name: &str, preserve_null_and_empty_arrays: bool, include_array_index: Option<&str>, path: KeyPath) -> Result<Vec<Value>> {
+        let Some(field) = model.field(field_name) else {
+            return Err(Error::new(format!("field `{}` is not defined on model `{}`", field_name, model.name())));
+        };
+        if !matches!(field.r#type().unwrap_optional(), FieldType::Array(_)) {
+            return Err(Error::new(format!("unwind requires an array field, but `{}` is not", field_name)));
+        }
+        let mut unwind = doc!{"path": format!("${}", field.column_name()), "preserveNullAndEmptyArrays": preserve_null_and_empty_arrays};
+        if let Some(include_array_index) = include_array_index {
+            unwind.insert("includeArrayIndex", include_array_index);
+        }
+        let mut aggregate_input = Aggregation::build(&self.options, namespace, model, finder)?;
+        aggregate_input.push(doc!{"$unwind": unwind});
+        let col = self.get_collection(model);
+        let results = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
+        Self::documents_to_values(results, path)
+    }
+
+    /// Run the finder on `model`, then promote the embedded document at
+    /// `new_root`, optionally unwinding it first when it's an array, and decode
+    /// the promoted documents as `target_model` objects. Promoted documents
+    /// without an `_id` inherit the one of the document they're embedded in.
+    pub async fn find_promoted(&self, model: &Model, finder: &Value, new_root: &str, unwind: bool, target_model: &Model, action: Action, transaction_ctx: Ctx, request: Option<Request>, path: KeyPath) -> Result<Vec<Object>> {
+        let mut aggregate_input = Aggregation::build(&self.options, transaction_ctx.namespace(), model, finder)?;
+        if unwind {
+            aggregate_input.push(doc!{"$unwind": format!("${new_root}")});
+        }
+        aggregate_input.push(doc!{"$replaceWith": {"$mergeObjects": [{"_id": "$_id"}, format!("${new_root}")]}});
+        let col = self.get_collection(model);
+        let results = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
+        let mut retval = vec![];
+        for result in results {
+            let document = match result {
+                Ok(document) => document,
+                Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            };
+            let obj = transaction_ctx.new_object(target_model, action, request.clone())?;
+            self.document_to_object(transaction_ctx.clone(), &document, &obj, None, None, &IndexMap::new())?;
+            retval.push(obj);
+        }
+        Ok(retval)
+    }
+
+    /// Like `find_many`, also returning how many records match `where`
+    /// regardless of pagination, in the same round trip. The page is
+    /// returned inside a single document, so it must stay below 16MB.
+    pub async fn find_many_with_total(&self, model: &Model, finder: &Value, action: Action, transaction_ctx: Ctx, request: Option<Request>, path: KeyPath) -> Result<(Vec<Object>, usize)> {
+        let select = finder.get("select");
+        let include = finder.get("include");
+        let (mut records_pipeline, aliases) = Aggregation::build_with_aliases(&self.options, transaction_ctx.namespace(), model, finder)?;
+        let count_finder = match finder.get("where") {
+            Some(r#where) => teon!({"where": r#where}),
+            None => teon!({}),
+        };
+        let total_pipeline = Aggregation::build_for_count(&self.options, transaction_ctx.namespace(), model, &count_finder)?;
+        // `$limit` must be positive, an empty page only counts
+        if finder.get("take").map_or(false, |take| take.to_int64().unwrap() == 0) {
+            records_pipeline = vec![doc!{"$match": {"$expr": false}}];
+        }
+        let aggregate_input = Self::facet_pipeline(records_pipeline, total_pipeline);
+        let col = self.get_collection(model);
+        let results = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
+        let facet = match results.into_iter().next() {
+            Some(Ok(facet)) => facet,
+            Some(Err(err)) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            None => return Err(error_ext::unknown_database_find_error(path, "no facet result")),
+        };
+        let (records, total) = Self::facet_result(&facet);
+        let records = records.into_iter().map(Ok).collect();
+        let objects = self.documents_to_objects(records, model, select, include, &aliases, Input::has_negative_take(finder), action, transaction_ctx, request, path)?;
+        Ok((objects, total))
+    }
+
+    /// Run the page and the count side by side in one `$facet`.
+    fn facet_pipeline(mut records_pipeline: Vec<Document>, mut total_pipeline: Vec<Document>) -> Vec<Document> {
+        let mut aggregate_input = vec![];
+        // a text search can't be inside `$facet`, both pipelines start with it
+        if records_pipeline.first().map_or(false, |stage| stage.get_document("$match").map_or(false, |m| m.contains_key("$text"))) {
+            aggregate_input.push(records_pipeline.remove(0));
+            total_pipeline.remove(0);
+        }
+        // `$facet` rejects empty pipelines
+        if records_pipeline.is_empty() {
+            records_pipeline.push(doc!{"$match": {}});
+        }
+        aggregate_input.push(doc!{"$facet": {"records": records_pipeline, "total": total_pipeline}});
+        aggregate_input
+    }
+
+    /// The page and the total of a `facet_pipeline` result. The count stage
+    /// outputs nothing when no record matches.
+    fn facet_result(facet: &Document) -> (Vec<Document>, usize) {
+        let total = facet.get_array("total").ok()
+            .and_then(|total| total.first())
+            .and_then(|count| count.as_document())
+            .and_then(|count| count.get("count"))
+            .and_then(|count| count.as_i64().or_else(|| count.as_i32().map(|c| c as i64)))
+            .unwrap_or(0) as usize;
+        let records = match facet.get_array("records") {
+            Ok(records) => records.iter().filter_map(|record| record.as_document().cloned()).collect(),
+            Err(_) => vec![],
+        };
+        (records, total)
+    }
+
+    /// Fetch the record of `model` a DBRef `{ "$ref", "$id", "$db" }` points
+    /// to, as legacy data links records this way instead of by foreign keys.
+    /// DBRefs decode as dictionaries of these keys. `None` when dangling.
+    pub async fn resolve_db_ref(&self, db_ref: &Value, model: &Model, action: Action, transaction_ctx: Ctx, request: Option<Request>, path: KeyPath) -> Result<Option<Object>> {
+        let filter = Self::db_ref_filter(db_ref, model.name(), self.options.collection_name(model), self.database.name())?;
+        let aggregate_input = vec![doc!{"$match": filter}, doc!{"$limit": 1}];
+        let col = self.get_collection(model);
+        let results = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
+        let Some(result) = results.into_iter().next() else {
+            return Ok(None);
+        };
+        let document = match result {
+            Ok(document) => document,
+            Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+        };
+        let obj = transaction_ctx.new_object(model, action, request)?;
+        self.document_to_object(transaction_ctx.clone(), &document, &obj, None, None, &IndexMap::new())?;
+        Ok(Some(obj))
+    }
+
+    /// The filter matching the record `db_ref` points to in `collection` of
+    /// `database`, which stores records of `model_name`.
+    fn db_ref_filter(db_ref: &Value, model_name: &str, collection: &str, database: &str) -> Result<Document> {
+        let (Some(ref_collection), Some(id)) = (db_ref.get("$ref").and_then(|r| r.as_str()), db_ref.get("$id")) else {
+            return Err(Error::new("a DBRef requires `$ref` and `$id`"));
+        };
+        if ref_collection != collection {
+            return Err(Error::new(format!("DBRef to `{}` doesn't point to a `{}` record", ref_collection, model_name)));
+        }
+        if let Some(ref_database) = db_ref.get("$db").and_then(|d| d.as_str()) {
+            if ref_database != database {
+                return Err(Error::new(format!("DBRef into database `{}` is not supported", ref_database)));
+            }
+        }
+        Ok(doc!{"_id": teon_value_to_bson(id)?})
+    }
+
+    /// Run the finder on `model` followed by the aggregation `stages`, e.g. a
+    /// `$group` building rollups, and `$merge` the output into `target_model`,
+    /// matching documents by the columns `on`. `when_matched` is either a
+    /// `$merge` action like `"replace"` or an update pipeline, where `$$new`
+    /// refers to the incoming document, so rollups accumulate across runs.
+    /// Unmatched documents are inserted. Fails inside a transaction.
+    pub async fn merge_into(&self, namespace: &Namespace, model: &Model, finder: &Value, stages: &Value, target_model: &Model, on: Vec<&str>, when_matched: &Value, path: KeyPath) -> Result<()> {
+        let Some(stages) = stages.as_array() else {
+            return Err(Error::new("merge stages must be an array"));
+        };
+        self.reject_merge_in_transaction("merging into another model")?;
+        let when_matched: Bson = match when_matched {
+            Value::String(action) => Bson::String(action.clone()),
+            Value::Array(pipeline) => Bson::Array(pipeline.iter().map(teon_value_to_bson).collect::<Result<Vec<Bson>>>()?),
+            _ => return Err(Error::new("whenMatched must be a merge action or an update pipeline")),
+        };
+        let mut aggregate_input = Aggregation::build(&self.options, namespace, model, finder)?;
+        for stage in stages {
+            match teon_value_to_bson(stage)? {
+                Bson::Document(stage) => aggregate_input.push(stage),
+                _ => return Err(Error::new("merge stage must be a dictionary")),
+            }
+        }
+        aggregate_input.push(doc!{"$merge": {
+            "into": self.options.collection_name(target_model),
+            "on": on,
+            "whenMatched": when_matched,
+            "whenNotMatched": "insert",
+        }});
+        let col = self.get_collection(model);
+        for result in self.aggregate_to_documents(aggregate_input, col, path.clone()).await? {
+            if let Err(err) = result {
+                return Err(error_ext::unknown_database_write_error(path, format!("{}", err)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Watch the changes of the records of `model`. The record after each
+    /// change is looked up, the one before it is only available for
+    /// collections migrated with `change_stream_pre_and_post_images`.
+    pub async fn watch<'a>(&'a self, model: &'a Model, action: Action, transaction_ctx: Ctx, request: Option<Request>, path: KeyPath) -> Result<BoxStream<'a, Result<ModelChange>>> {
+        let mut options = ChangeStreamOptions::default();
+        options.full_document = Some(FullDocumentType::UpdateLookup);
+        if self.options.change_stream_pre_and_post_images {
+            self.require_server_version(6, 0, "change stream pre-images").await?;
+            options.full_document_before_change = Some(FullDocumentBeforeChangeType::WhenAvailable);
+        }
+        let col = self.get_collection(model);
+        let stream = match col.watch(vec![], options).await {
+            Ok(stream) => stream,
+            Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+        };
+        Ok(Box::pin(stream.map(move |event: std::result::Result<ChangeStreamEvent<Document>, MongoDBError>| {
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => return Err(error_ext::unknown_database_find_error(path.clone(), format!("{}", err))),
+            };
+            let decode = |document: Option<Document>| -> Result<Option<Object>> {
+                let Some(document) = document else {
+                    return Ok(None);
+                };
+                let object = transaction_ctx.new_object(model, action, request.clone())?;
+                self.document_to_object(transaction_ctx.clone(), &document, &object, None, None, &IndexMap::new())?;
+                Ok(Some(object))
+            };
+            Ok(ModelChange {
+                operation: event.operation_type,
+                before: decode(event.full_document_before_change)?,
+                after: decode(event.full_document)?,
+            })
+        })))
+    }
+
+    /// `$merge` can't run inside a transaction, so fail `operation` clearly
+    /// instead of with the server's error.
+    fn reject_merge_in_transaction(&self, operation: &str) -> Result<()> {
+        if self.session().is_some() {
+            return Err(Error::new(format!("{} writes with `$merge`, which can't run inside a transaction", operation)));
+        }
         Ok(())
     }
 
+    /// Count the related records of `relation_name` for every record of `model`
+    /// and store the number into `counter_field`, including zero counts.
+    pub async fn recompute_relation_count(&self, namespace: &Namespace, model: &Model, relation_name: &str, counter_field: &str, path: KeyPath) -> Result<()> {
+        let Some(counter) = model.field(counter_field) else {
+            return Err(Error::new(format!("field `{}` is not defined on model `{}`", counter_field, model.name())));
+        };
+        // counted like `relationCount`, joining the same way includes do
+        let mut aliases = IndexMap::new();
+        let mut pipeline = Aggregation::build_relation_counts(&self.options, namespace, model, &teon!({relation_name: {"into": counter_field}}), &mut aliases)?;
+        let alias = aliases.keys().next().unwrap();
+        let column_name = counter.column_name();
+        pipeline.push(doc!{"$project": {column_name: format!("${alias}")}});
+        let col = self.get_collection(model);
+        if self.session().is_none() {
+            pipeline.push(doc!{"$merge": {"into": self.options.collection_name(model), "on": "_id", "whenMatched": "merge", "whenNotMatched": "discard"}});
+            for result in self.aggregate_to_documents(pipeline, col, path.clone()).await? {
+                if let Err(err) = result {
+                    return Err(error_ext::unknown_database_write_error(path, format!("{}", err)));
+                }
+            }
+            return Ok(())
+        }
+        // `$merge` can't run inside a transaction, the counts are read and
+        // written back with one update per distinct count instead
+        let mut rows = vec![];
+        for result in self.aggregate_to_documents(pipeline, col.clone(), path.clone()).await? {
+            match result {
+                Ok(row) => rows.push(row),
+                Err(err) => return Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            }
+        }
+        for (filter, update) in Self::relation_count_updates(column_name, rows) {
+            let result = match self.session() {
+                None => col.update_many(filter, update, None).await,
+                Some(session) => col.update_many_with_session(filter, update, None, session).await,
+            };
+            if let Err(err) = result {
+                return Err(error_ext::unknown_database_write_error(path, format!("{}", err)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Groups the `{ _id, column: count }` rows by count into `(filter,
+    /// update)` pairs, splitting large groups to keep each filter small.
+    fn relation_count_updates(column_name: &str, rows: Vec<Document>) -> Vec<(Document, Document)> {
+        let mut ids_by_count: IndexMap<i64, (Bson, Vec<Bson>)> = IndexMap::new();
+        for mut row in rows {
+            let Some(id) = row.remove("_id") else {
+                continue
+            };
+            let count = row.remove(column_name).unwrap_or(Bson::Int32(0));
+            let key = match &count {
+                Bson::Int32(n) => *n as i64,
+                Bson::Int64(n) => *n,
+                _ => 0,
+            };
+            ids_by_count.entry(key).or_insert_with(|| (count, vec![])).1.push(id);
+        }
+        let mut retval = vec![];
+        for (_, (count, ids)) in ids_by_count {
+            for ids in ids.chunks(RELATION_COUNT_UPDATE_BATCH) {
+                retval.push((doc!{"_id": {"$in": ids}}, doc!{"$set": {column_name: count.clone()}}));
+            }
+        }
+        retval
+    }
+}
+
+#[async_trait]
+impl Transaction for MongoDBTransaction {
+
+    async fn migrate(&self, models: Vec<&Model>, dry_run: bool, reset_database: bool, silent: bool) -> Result<()> {
+        if reset_database {
+            let _ = self.database.drop(None).await;
+        }
+        // index operations on different collections are independent
+        let concurrency = self.options.migration_concurrency.unwrap_or(DEFAULT_MIGRATION_CONCURRENCY).max(1);
+        let completed: Mutex<Vec<&str>> = Mutex::new(vec![]);
+        let failed: Mutex<Vec<&str>> = Mutex::new(vec![]);
+        let pending: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
+        let migration = futures_util::stream::iter(models.iter())
+            .map(|model| async {
+                let result = self.migrate_model(model, &pending).await;
+                match &result {
+                    Ok(()) => completed.lock().unwrap().push(model.name()),
+                    Err(_) => failed.lock().unwrap().push(model.name()),
+                }
+                result
+            })
+            .buffer_unordered(concurrency)
+            .filter_map(|result| async move { result.err() })
+            .collect::<Vec<Error>>();
+        let errors = match self.options.migration_deadline {
+            None => migration.await,
+            Some(deadline) => match tokio::time::timeout(deadline, migration).await {
+                Ok(errors) => errors,
+                Err(_) => {
+                    // dropping an index while it's built aborts the build
+                    let builds = pending.lock().unwrap().clone();
+                    let mut aborted = vec![];
+                    let mut not_aborted = vec![];
+                    for (collection, index) in builds {
+                        match self.database.collection::<Document>(&collection).drop_index(&index, None).await {
+                            Ok(()) => aborted.push(format!("{}.{}", collection, index)),
+                            Err(err) => not_aborted.push(format!("{}.{} ({})", collection, index, err)),
+                        }
+                    }
+                    let names: Vec<&str> = models.iter().map(|model| model.name()).collect();
+                    let completed = completed.lock().unwrap().clone();
+                    let failed = failed.lock().unwrap().clone();
+                    return Err(Error::new(Self::migration_deadline_report(deadline, &names, &completed, &failed, &aborted, &not_aborted)));
+                }
+            }
+        };
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(errors.iter().map(|e| format!("{}", e)).join("\n")))
+        }
+    }
+
     async fn purge(&self, models: Vec<&Model>) -> Result<()> {
         for model in models {
             let col = self.get_collection(model);
@@ -470,8 +2020,13 @@ impl Transaction for MongoDBTransaction {
         Ok(())
     }
 
+    /// `{ collection, pipeline }` runs the aggregation `pipeline` on the
+    /// collection as is, returning the output documents.
     async fn query_raw(&self, value: &Value) -> Result<Value> {
-        unreachable!()
+        let (collection, aggregate_input) = Self::raw_query_input(value)?;
+        let col = self.get_collection_named(collection);
+        let results = self.aggregate_to_documents(aggregate_input, col, path![]).await?;
+        Ok(Value::Array(Self::documents_to_values(results, path![])?))
     }
 
     async fn save_object(&self, object: &Object, path: KeyPath) -> Result<()> {
@@ -487,13 +2042,15 @@ impl Transaction for MongoDBTransaction {
             return Err(error_ext::object_is_not_saved_thus_cant_be_deleted(path));
         }
         let model = object.model();
-        let col = self.get_collection(model);
-        let bson_identifier: Bson = teon_value_to_bson(&object.db_identifier());
-        let document_identifier = bson_identifier.as_document().unwrap();
+        let col = self.get_write_collection(model, "delete");
+        let document_identifier = &self.identifier_filter(object)?;
+        let options = DeleteOptions::builder().comment(self.comment_bson()).build();
+        let started = Instant::now();
         let result = match self.session() {
-            None => col.delete_one(document_identifier.clone(), None).await,
-            Some(session) => col.delete_one_with_session(document_identifier.clone(), None, session).await,
+            None => col.delete_one(document_identifier.clone(), options).await,
+            Some(session) => col.delete_one_with_session(document_identifier.clone(), options, session).await,
         };
+        self.report_slow_query("delete", col.name(), started, None);
         return match result {
             Ok(_result) => Ok(()),
             Err(err) => {
@@ -505,15 +2062,18 @@ impl Transaction for MongoDBTransaction {
     async fn find_unique(&self, model: &Model, finder: &Value, ignore_select_and_include: bool, action: Action, transaction_ctx: Ctx, request: Option<Request>, path: KeyPath) -> Result<Option<Object>> {
         let select = finder.get("select");
         let include = finder.get("include");
-        let aggregate_input = Aggregation::build(transaction_ctx.namespace(), model, finder)?;
+        let (aggregate_input, aliases) = Aggregation::build_with_aliases(&self.options, transaction_ctx.namespace(), model, finder)?;
         let col = self.get_collection(model);
-        let results = self.aggregate_to_documents(aggregate_input, col, path).await?;
+        let results = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
+        if self.options.strict_find_unique && results.len() > 1 {
+            return Err(error_ext::unknown_database_find_error(path, format!("{} records of {} match a unique filter, a unique index may be missing", results.len(), model.name())));
+        }
         if results.is_empty() {
             Ok(None)
         } else {
             for doc in results {
                 let obj = transaction_ctx.new_object(model, action, request)?;
-                self.clone().document_to_object(transaction_ctx, &doc.unwrap(), &obj, select, include)?;
+                self.clone().document_to_object(transaction_ctx, &doc.unwrap(), &obj, select, include, &aliases)?;
                 return Ok(Some(obj));
             }
             Ok(None)
@@ -521,30 +2081,118 @@ impl Transaction for MongoDBTransaction {
     }
 
     async fn find_many(&self, model: &Model, finder: &Value, ignore_select_and_include: bool, action: Action, transaction_ctx: Ctx, request: Option<Request>, path: KeyPath) -> Result<Vec<Object>> {
+        // `$limit` must be positive, an empty page needs no round trip
+        if finder.get("take").map_or(false, |take| take.to_int64().unwrap() == 0) {
+            return Ok(vec![]);
+        }
         let select = finder.get("select");
         let include = finder.get("include");
-        let aggregate_input = Aggregation::build(transaction_ctx.namespace(), model, finder)?;
+        if let (Some(include), Some(threshold)) = (include, self.options.lookup_warning_threshold) {
+            self.guard_lookups(model, finder, include, threshold, transaction_ctx.clone(), path.clone()).await?;
+        }
+        let (aggregate_input, aliases) = Aggregation::build_with_aliases(&self.options, transaction_ctx.namespace(), model, finder)?;
         let reverse = Input::has_negative_take(finder);
         let col = self.get_collection(model);
-        // println!("see aggregate input: {:?}", aggregate_input);
-        let mut result = vec![];
         let results: Vec<std::result::Result<Document, MongoDBError>> = self.aggregate_to_documents(aggregate_input, col, path.clone()).await?;
-        for doc in results {
-            let obj = transaction_ctx.new_object(model, action, request.clone())?;
-            match self.clone().document_to_object(transaction_ctx.clone(), &doc.unwrap(), &obj, select, include) {
-                Ok(_) => {
-                    if reverse {
-                        result.insert(0, obj);
-                    } else {
-                        result.push(obj);
-                    }
-                }
-                Err(err) => {
-                    return Err(error_ext::unknown_database_find_error(path, format!("{}", err)));
-                }
-            }
+        if Self::decodes_off_task(self.options.blocking_decode_threshold, results.len()) {
+            let transaction = self.clone();
+            let model = model.clone();
+            let select = select.cloned();
+            let include = include.cloned();
+            // decoding reads the options and never the session, which the
+            // unsafe `Send` of the transaction guards, and this task waits
+            // for it, so the clone isn't used alongside the original
+            Self::decode_off_task(move || {
+                transaction.documents_to_objects(results, &model, select.as_ref(), include.as_ref(), &aliases, reverse, action, transaction_ctx, request, path)
+            }).await
+        } else {
+            self.documents_to_objects(results, model, select, include, &aliases, reverse, action, transaction_ctx, request, path)
         }
-        Ok(result)
+    }
+
+    /// Whether `count` documents are decoded on the blocking pool, given
+    /// `blocking_decode_threshold`.
+    fn decodes_off_task(threshold: Option<usize>, count: usize) -> bool {
+        threshold.map_or(false, |threshold| count >= threshold)
+    }
+
+    /// Run `decode` on the blocking pool, so large results don't hold up
+    /// the other tasks of the runtime.
+    async fn decode_off_task<T: Send + 'static>(decode: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+        match tokio::task::spawn_blocking(decode).await {
+            Ok(result) => result,
+            Err(err) => Err(Error::new(format!("decoding task failed: {}", err))),
+        }
+    }
+
+    /// Count the records `finder` includes relations for, unless its `take`
+    /// is below `threshold`, and warn or fail as `lookup_guard` tells when
+    /// there are at least `threshold` of them, before any lookup runs.
+    async fn guard_lookups(&self, model: &Model, finder: &Value, include: &Value, threshold: usize, transaction_ctx: Ctx, path: KeyPath) -> Result<()> {
+        if Self::take_below(finder, threshold) {
+            return Ok(());
+        }
+        let count_finder = Value::Dictionary(finder.as_dictionary().unwrap().iter().filter(|(k, _)| !matches!(k.as_str(), "include" | "select")).map(|(k, v)| (k.clone(), v.clone())).collect());
+        let records = self.count_objects(model, &count_finder, transaction_ctx, path.clone()).await?;
+        Self::check_lookup_count(&self.options, self.options.collection_name(model), include, records, threshold)
+            .map_err(|message| error_ext::unknown_database_find_error(path, message))
+    }
+
+    /// Warns about or, under `LookupGuard::Reject`, returns the message of
+    /// included lookups over at least `threshold` records.
+    fn check_lookup_count(options: &ConnectionOptions, collection: &str, include: &Value, records: usize, threshold: usize) -> std::result::Result<(), String> {
+        if records < threshold {
+            return Ok(());
+        }
+        let relations = include.as_dictionary().unwrap().keys().join(", ");
+        let message = format!("included relations [{}] of {} would run per record over {} records, consider paginating", relations, collection, records);
+        if options.lookup_guard == LookupGuard::Reject {
+            return Err(message);
+        }
+        options.warn(&format!("warning: {}", message));
+        Ok(())
+    }
+
+    /// Whether `column_name` belongs to the primary key `identifier` and
+    /// `value` leaves it as stored. Fails when the write would change it, or
+    /// for atomic updates, given as `None`, of it: the server rejects
+    /// changing `_id`, and other primary keys identify the record.
+    fn primary_key_unchanged(identifier: &Document, column_name: &str, key: &str, value: Option<&Bson>) -> std::result::Result<bool, String> {
+        match (identifier.get(column_name), value) {
+            (None, _) => Ok(false),
+            (Some(stored), Some(value)) if stored == value => Ok(true),
+            (Some(_), _) => Err(format!("cannot modify primary key `{}`", key)),
+        }
+    }
+
+    /// The `$set` of `EmptyUpdate::Touch`, moving the field to the current
+    /// time at the precision the server stores, and that time.
+    fn touch_update(options: &ConnectionOptions, r#type: &FieldType, column_name: &str) -> Result<(Document, Value)> {
+        let now = Value::DateTime(Utc::now().trunc_subsecs(3));
+        Ok((doc!{column_name: BsonCoder::encode(options, r#type, now.clone())?}, now))
+    }
+
+    /// The `$bit` operand of a `bitAnd`, `bitOr` or `bitXor` updator, e.g.
+    /// `{ "or": 4 }`, of the field's integer width.
+    fn bit_operand(r#type: &FieldType, key: &str, val: &Value, name: &str) -> Result<Document> {
+        let operator = key.strip_prefix("bit").unwrap().to_lowercase();
+        let operand = match (r#type.unwrap_optional(), val) {
+            (FieldType::Int, Value::Int(n)) => Bson::Int32(*n),
+            (FieldType::Int, Value::Int64(n)) => match i32::try_from(*n) {
+                Ok(n) => Bson::Int32(n),
+                Err(_) => return Err(Error::new(format!("`{}` of `{}` is out of range", key, name))),
+            },
+            (FieldType::Int64, Value::Int(n)) => Bson::Int64(*n as i64),
+            (FieldType::Int64, Value::Int64(n)) => Bson::Int64(*n),
+            (FieldType::Int | FieldType::Int64, _) => return Err(Error::new(format!("`{}` of `{}` takes an integer", key, name))),
+            _ => return Err(Error::new(format!("bitwise update requires an integer field, but `{}` is not", name))),
+        };
+        Ok(doc!{operator: operand})
+    }
+
+    /// Whether `finder` takes fewer than `threshold` records.
+    fn take_below(finder: &Value, threshold: usize) -> bool {
+        finder.get("take").and_then(|take| take.to_int64()).map_or(false, |take| (take.unsigned_abs() as usize) < threshold)
     }
 
     async fn count(&self, model: &Model, finder: &Value, transaction_ctx: Ctx, path: KeyPath) -> Result<Value> {
@@ -557,7 +2205,14 @@ impl Transaction for MongoDBTransaction {
     }
 
     async fn count_objects(&self, model: &Model, finder: &Value, transaction_ctx: Ctx, path: KeyPath) -> Result<usize> {
-        let input = Aggregation::build_for_count(transaction_ctx.namespace(), model, finder)?;
+        if self.options.estimated_unfiltered_count && self.owned_session.is_none() && Self::is_unfiltered(finder) {
+            let col = self.get_collection(model);
+            return match col.estimated_document_count(None).await {
+                Ok(count) => Ok(count as usize),
+                Err(err) => Err(error_ext::unknown_database_find_error(path, format!("{}", err))),
+            };
+        }
+        let input = Aggregation::build_for_count(&self.options, transaction_ctx.namespace(), model, finder)?;
         let col = self.get_collection(model);
         let results = self.aggregate_to_documents(input, col, path).await?;
         if results.is_empty() {
@@ -591,9 +2246,16 @@ impl Transaction for MongoDBTransaction {
             // there is no record
             let mut retval = teon!({});
             for (g, o) in finder.as_dictionary().unwrap() {
+                if !["_count", "_sum", "_avg", "_min", "_max", "_push", "_addToSet"].contains(&g.as_str()) {
+                    continue;
+                }
                 retval.as_dictionary_mut().unwrap().insert(g.clone(), teon!({}));
                 for (k, _v) in o.as_dictionary().unwrap() {
-                    let value = if g == "_count" { teon!(0) } else { teon!(null) };
+                    let value = match g.as_str() {
+                        "_count" => teon!(0),
+                        "_push" | "_addToSet" => teon!([]),
+                        _ => teon!(null),
+                    };
                     retval.as_dictionary_mut().unwrap().get_mut(g.as_str()).unwrap().as_dictionary_mut().unwrap().insert(k.to_string(), value);
                 }
             }
@@ -642,3 +2304,584 @@ impl Transaction for MongoDBTransaction {
 
 unsafe impl Sync for MongoDBTransaction {}
 unsafe impl Send for MongoDBTransaction {}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use bigdecimal::BigDecimal;
+    use bson::{doc, Bson, Document};
+    use bson::oid::ObjectId;
+    use chrono::{SubsecRound, Utc};
+    use key_path::path;
+    use mongodb::Client;
+    use mongodb::error::{BulkWriteFailure, Error as MongoDBError};
+    use mongodb::options::ClientOptions;
+    use teo_parser::r#type::Type;
+    use teo_runtime::{teon, Value};
+    use crate::connector::{ConnectionOptions, EmptyArray, FractionalMultiplier, LookupGuard, OperationOptions, WarningHandler};
+    use crate::bson_ext::document_to_teon_value;
+    use super::{Factor, MongoDBTransaction};
+
+    fn times(stored: Bson, multiplier: &str, r#type: Type, fractional_multiplier: FractionalMultiplier) -> teo_result::Result<Bson> {
+        MongoDBTransaction::exact_product(&r#type, "value", &stored, &Factor::Times(BigDecimal::from_str(multiplier).unwrap()), fractional_multiplier)
+    }
+
+    #[test]
+    fn integral_product_of_a_fractional_multiplier_is_stored() {
+        assert_eq!(times(Bson::Int32(10), "0.5", Type::Int, FractionalMultiplier::Reject).unwrap(), Bson::Int32(5));
+        assert_eq!(times(Bson::Int64(10), "0.5", Type::Int64, FractionalMultiplier::Reject).unwrap(), Bson::Int64(5));
+    }
+
+    #[test]
+    fn fractional_product_is_rejected() {
+        assert!(times(Bson::Int32(3), "0.5", Type::Int, FractionalMultiplier::Reject).is_err());
+    }
+
+    #[test]
+    fn fractional_product_is_rounded_half_away_from_zero() {
+        assert_eq!(times(Bson::Int32(3), "0.5", Type::Int, FractionalMultiplier::Round).unwrap(), Bson::Int32(2));
+        assert_eq!(times(Bson::Int32(-3), "0.5", Type::Int, FractionalMultiplier::Round).unwrap(), Bson::Int32(-2));
+        assert_eq!(times(Bson::Int32(3), "0.4", Type::Int, FractionalMultiplier::Round).unwrap(), Bson::Int32(1));
+    }
+
+    #[test]
+    fn product_out_of_range_is_rejected() {
+        assert!(times(Bson::Int32(i32::MAX), "1.5", Type::Int, FractionalMultiplier::Round).is_err());
+    }
+
+    #[test]
+    fn missing_value_counts_as_zero() {
+        assert_eq!(times(Bson::Null, "0.5", Type::Int, FractionalMultiplier::Reject).unwrap(), Bson::Int32(0));
+    }
+
+    fn over(stored: Bson, divisor: i64, r#type: Type) -> teo_result::Result<Bson> {
+        MongoDBTransaction::exact_product(&r#type, "value", &stored, &Factor::Over(BigDecimal::from(divisor)), FractionalMultiplier::Reject)
+    }
+
+    #[test]
+    fn quotient_keeps_the_sign() {
+        assert_eq!(over(Bson::Int32(10), -2, Type::Int).unwrap(), Bson::Int32(-5));
+        assert_eq!(over(Bson::Int32(-10), -2, Type::Int).unwrap(), Bson::Int32(5));
+        assert_eq!(over(Bson::Int64(-10), 2, Type::Int64).unwrap(), Bson::Int64(-5));
+    }
+
+    #[test]
+    fn division_by_zero_is_rejected() {
+        assert!(MongoDBTransaction::divisor("value", &Value::Int(0)).is_err());
+        assert!(MongoDBTransaction::divisor("value", &Value::Float(0.0)).is_err());
+        assert_eq!(MongoDBTransaction::divisor("value", &Value::Int(-2)).unwrap(), BigDecimal::from(-2));
+    }
+
+    #[test]
+    fn exact_integer_quotient_is_stored() {
+        assert_eq!(over(Bson::Int32(10), 2, Type::Int).unwrap(), Bson::Int32(5));
+        assert_eq!(over(Bson::Int32(49), 49, Type::Int).unwrap(), Bson::Int32(1));
+    }
+
+    #[test]
+    fn fractional_integer_quotient_is_rejected() {
+        assert!(over(Bson::Int32(10), 4, Type::Int).is_err());
+    }
+
+    #[test]
+    fn decimal_quotient_keeps_decimal128_precision() {
+        let quotient = MongoDBTransaction::exact_product(&Type::Decimal, "value", &Bson::Int32(1), &Factor::Over(BigDecimal::from(3)), FractionalMultiplier::Reject).unwrap();
+        let Bson::Decimal128(quotient) = quotient else {
+            panic!("expected a Decimal128");
+        };
+        assert_eq!(quotient.to_string(), format!("0.{}", "3".repeat(34)));
+    }
+
+    #[test]
+    fn migration_deadline_report_tells_models_apart() {
+        let report = MongoDBTransaction::migration_deadline_report(Duration::from_secs(1), &["A", "B", "C", "D"], &["A"], &["B"], &["records.c_1".to_owned()], &[]);
+        assert_eq!(report, "migration exceeded its deadline of 1s, completed: [A], failed: [B], unfinished: [C, D], aborted index builds: [records.c_1]");
+    }
+
+    #[test]
+    fn migration_deadline_report_names_builds_which_may_still_run() {
+        let report = MongoDBTransaction::migration_deadline_report(Duration::from_secs(1), &["A"], &[], &[], &[], &["records.a_1 (not found)".to_owned()]);
+        assert_eq!(report, "migration exceeded its deadline of 1s, completed: [], failed: [], unfinished: [A], index builds which may still run: [records.a_1 (not found)]");
+    }
+
+    #[test]
+    fn pages_taking_fewer_records_than_the_threshold_are_not_counted() {
+        assert!(MongoDBTransaction::take_below(&teon!({"take": 10}), 100));
+        assert!(MongoDBTransaction::take_below(&teon!({"take": -10}), 100));
+    }
+
+    #[test]
+    fn unbounded_or_large_pages_are_counted() {
+        assert!(!MongoDBTransaction::take_below(&teon!({}), 100));
+        assert!(!MongoDBTransaction::take_below(&teon!({"take": 100}), 100));
+        assert!(!MongoDBTransaction::take_below(&teon!({"take": -500}), 100));
+    }
+
+    #[test]
+    fn read_retries_back_off_exponentially_up_to_the_cap() {
+        assert_eq!(MongoDBTransaction::read_retry_delay(1), Duration::from_millis(100));
+        assert_eq!(MongoDBTransaction::read_retry_delay(2), Duration::from_millis(200));
+        assert_eq!(MongoDBTransaction::read_retry_delay(4), Duration::from_millis(800));
+        assert_eq!(MongoDBTransaction::read_retry_delay(6), Duration::from_secs(2));
+        assert_eq!(MongoDBTransaction::read_retry_delay(40), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn pushed_arrays_add_each_element() {
+        let options = ConnectionOptions::default();
+        let operand = MongoDBTransaction::array_updator_operand(&options, "push", &Type::Int, &teon!([1, 2]), "scores").unwrap();
+        assert_eq!(operand, Bson::Document(doc!{"$each": [1, 2]}));
+        let operand = MongoDBTransaction::array_updator_operand(&options, "addToSet", &Type::Int, &teon!(1), "scores").unwrap();
+        assert_eq!(operand, Bson::Int32(1));
+    }
+
+    #[test]
+    fn pushed_arrays_of_arrays_add_one_element() {
+        let options = ConnectionOptions::default();
+        let operand = MongoDBTransaction::array_updator_operand(&options, "push", &Type::Array(Box::new(Type::Int)), &teon!([1, 2]), "matrix").unwrap();
+        assert_eq!(operand, Bson::Array(vec![Bson::Int32(1), Bson::Int32(2)]));
+    }
+
+    #[test]
+    fn pushed_values_of_another_type_are_rejected() {
+        let options = ConnectionOptions::default();
+        assert!(MongoDBTransaction::array_updator_operand(&options, "push", &Type::Int, &teon!("1"), "scores").is_err());
+        assert!(MongoDBTransaction::array_updator_operand(&options, "addToSet", &Type::Int, &teon!(["1"]), "scores").is_err());
+    }
+
+    #[test]
+    fn pulled_arrays_remove_each_element() {
+        let options = ConnectionOptions::default();
+        let operand = MongoDBTransaction::array_updator_operand(&options, "pull", &Type::Int, &teon!([1, 2]), "scores").unwrap();
+        assert_eq!(operand, Bson::Document(doc!{"$in": [1, 2]}));
+        let operand = MongoDBTransaction::array_updator_operand(&options, "pull", &Type::Int, &teon!(1), "scores").unwrap();
+        assert_eq!(operand, Bson::Int32(1));
+    }
+
+    #[test]
+    fn repairs_convert_without_failing_and_back_up_the_original() {
+        let pipeline = MongoDBTransaction::repair_pipeline("count", "int");
+        assert_eq!(pipeline, vec![
+            doc!{"$set": {"__repairing_count": {"$convert": {"input": "$count", "to": "int", "onError": null, "onNull": null}}}},
+            doc!{"$set": {
+                "count": {"$cond": [{"$eq": ["$__repairing_count", null]}, "$count", "$__repairing_count"]},
+                "__before_repair_count": {"$cond": [{"$eq": ["$__repairing_count", null]}, "$$REMOVE", "$count"]},
+            }},
+            doc!{"$unset": "__repairing_count"},
+        ]);
+    }
+
+    #[test]
+    fn repairs_of_bools_only_accept_unambiguous_values() {
+        let pipeline = MongoDBTransaction::repair_pipeline("active", "bool");
+        assert_eq!(pipeline[0], doc!{"$set": {"__repairing_active": {"$switch": {"branches": [
+            {"case": {"$in": ["$active", ["true", 1]]}, "then": true},
+            {"case": {"$in": ["$active", ["false", 0]]}, "then": false},
+        ], "default": null}}}});
+    }
+
+    #[test]
+    fn repairs_target_the_declared_type() {
+        assert_eq!(MongoDBTransaction::repair_bson_type(&Type::Int), Some("int"));
+        assert_eq!(MongoDBTransaction::repair_bson_type(&Type::Optional(Box::new(Type::DateTime))), Some("date"));
+        assert_eq!(MongoDBTransaction::repair_bson_type(&Type::Array(Box::new(Type::Int))), None);
+    }
+
+    #[test]
+    fn clamped_decrements_require_the_amount() {
+        let options = ConnectionOptions::default();
+        let (condition, update) = MongoDBTransaction::clamped_decrement(&options, &Type::Int, "balance", "balance", &Value::Int(30)).unwrap();
+        assert_eq!(condition, doc!{"balance": {"$gte": 30}});
+        assert_eq!(update, doc!{"$inc": {"balance": -30}});
+        let (condition, update) = MongoDBTransaction::clamped_decrement(&options, &Type::Float, "credit", "credit", &Value::Float(2.5)).unwrap();
+        assert_eq!(condition, doc!{"credit": {"$gte": 2.5}});
+        assert_eq!(update, doc!{"$inc": {"credit": -2.5}});
+    }
+
+    #[test]
+    fn clamped_decrements_reject_amounts_which_arent_positive() {
+        let options = ConnectionOptions::default();
+        assert!(MongoDBTransaction::clamped_decrement(&options, &Type::Int, "balance", "balance", &Value::Int(0)).is_err());
+        assert!(MongoDBTransaction::clamped_decrement(&options, &Type::Int, "balance", "balance", &Value::Int(-5)).is_err());
+        assert!(MongoDBTransaction::clamped_decrement(&options, &Type::Float, "credit", "credit", &Value::Float(f64::NAN)).is_err());
+    }
+
+    #[test]
+    fn clamped_decrements_reject_amounts_of_another_type() {
+        let options = ConnectionOptions::default();
+        assert!(MongoDBTransaction::clamped_decrement(&options, &Type::Int, "balance", "balance", &Value::Int64(5)).is_err());
+        assert!(MongoDBTransaction::clamped_decrement(&options, &Type::Float, "credit", "credit", &Value::String("5".to_owned())).is_err());
+    }
+
+    #[test]
+    fn save_keys_are_written_to_their_columns() {
+        // a field mapped to another column
+        assert_eq!(MongoDBTransaction::column_of("authorId", Some("author_id")), "author_id");
+        // a field stored under its own name
+        assert_eq!(MongoDBTransaction::column_of("title", Some("title")), "title");
+        // a property, which has no column
+        assert_eq!(MongoDBTransaction::column_of("fullName", None), "fullName");
+    }
+
+    #[test]
+    fn unique_keys_are_inserted() {
+        let mut document = doc!{"author_id": 1};
+        MongoDBTransaction::insert_unique(&mut document, "title", Bson::String("Hello".to_owned()), "title").unwrap();
+        assert_eq!(document, doc!{"author_id": 1, "title": "Hello"});
+    }
+
+    #[test]
+    fn keys_already_written_are_rejected() {
+        // the field `authorId` is stored as `author_id`, which a property also writes
+        let mut document = doc!{"author_id": 1};
+        let error = MongoDBTransaction::insert_unique(&mut document, "author_id", Bson::Int32(2), "author_id").unwrap_err();
+        assert!(error.to_string().contains("`author_id`"));
+        assert_eq!(document, doc!{"author_id": 1});
+    }
+
+    #[test]
+    fn empty_arrays_are_stored_by_default() {
+        assert_eq!(MongoDBTransaction::stored_field_value(EmptyArray::Store, Bson::Array(vec![])), Some(Bson::Array(vec![])));
+    }
+
+    #[test]
+    fn empty_arrays_are_stored_as_null() {
+        // created and `$set` as null, so the field still passes `$exists`
+        assert_eq!(MongoDBTransaction::stored_field_value(EmptyArray::Null, Bson::Array(vec![])), Some(Bson::Null));
+    }
+
+    #[test]
+    fn empty_arrays_are_omitted() {
+        // skipped on create and `$unset` on update
+        assert_eq!(MongoDBTransaction::stored_field_value(EmptyArray::Omit, Bson::Array(vec![])), None);
+    }
+
+    #[test]
+    fn null_scalars_are_never_stored() {
+        for empty_array in [EmptyArray::Store, EmptyArray::Null, EmptyArray::Omit] {
+            assert_eq!(MongoDBTransaction::stored_field_value(empty_array, Bson::Null), None);
+            assert_eq!(MongoDBTransaction::stored_field_value(empty_array, Bson::Array(vec![Bson::Int32(1)])), Some(Bson::Array(vec![Bson::Int32(1)])));
+        }
+    }
+
+    fn bulk_write_failure(write_errors: Vec<(usize, &str)>) -> BulkWriteFailure {
+        let write_errors: Vec<Bson> = write_errors.into_iter().map(|(index, message)| Bson::Document(doc!{"index": index as i64, "code": 11000, "errmsg": message})).collect();
+        bson::from_document(doc!{"writeErrors": write_errors}).unwrap()
+    }
+
+    #[test]
+    fn ordered_batches_stop_at_the_failed_record() {
+        let failures = MongoDBTransaction::batch_failures(&bulk_write_failure(vec![(3, "duplicate key")])).unwrap();
+        let stored: Vec<bool> = (0..6).map(|index| MongoDBTransaction::batch_stored(true, &failures, index)).collect();
+        assert_eq!(stored, vec![true, true, true, false, false, false]);
+        assert_eq!(MongoDBTransaction::batch_error(true, &failures, 6), Some((Some(3), "record 3 failed: duplicate key; the 3 records before it were stored, the 2 after it were not".to_owned())));
+    }
+
+    #[test]
+    fn unordered_batches_store_all_but_the_failed_records() {
+        let failures = MongoDBTransaction::batch_failures(&bulk_write_failure(vec![(1, "duplicate key"), (4, "validation failed")])).unwrap();
+        let stored: Vec<bool> = (0..6).map(|index| MongoDBTransaction::batch_stored(false, &failures, index)).collect();
+        assert_eq!(stored, vec![true, false, true, true, false, true]);
+        assert_eq!(MongoDBTransaction::batch_error(false, &failures, 6), Some((None, "2 of 6 records failed, the others were stored: record 1: duplicate key; record 4: validation failed".to_owned())));
+    }
+
+    #[test]
+    fn batches_failing_only_the_write_concern_have_no_failed_records() {
+        let failure: BulkWriteFailure = bson::from_document(doc!{"writeConcernError": {"code": 64, "errmsg": "waiting for replication timed out"}}).unwrap();
+        assert!(MongoDBTransaction::batch_failures(&failure).is_none());
+        assert_eq!(MongoDBTransaction::batch_error(true, &[], 6), None);
+    }
+
+    #[test]
+    fn stored_db_refs_resolve_to_their_record() {
+        let oid = ObjectId::new();
+        let db_ref = document_to_teon_value(&doc!{"$ref": "users", "$id": oid, "$db": "shop"});
+        assert_eq!(MongoDBTransaction::db_ref_filter(&db_ref, "User", "users", "shop").unwrap(), doc!{"_id": oid});
+        let db_ref = document_to_teon_value(&doc!{"$ref": "users", "$id": oid});
+        assert_eq!(MongoDBTransaction::db_ref_filter(&db_ref, "User", "users", "shop").unwrap(), doc!{"_id": oid});
+    }
+
+    #[test]
+    fn db_refs_elsewhere_are_rejected() {
+        let oid = ObjectId::new();
+        let db_ref = document_to_teon_value(&doc!{"$ref": "posts", "$id": oid});
+        assert!(MongoDBTransaction::db_ref_filter(&db_ref, "User", "users", "shop").is_err());
+        let db_ref = document_to_teon_value(&doc!{"$ref": "users", "$id": oid, "$db": "archive"});
+        assert!(MongoDBTransaction::db_ref_filter(&db_ref, "User", "users", "shop").is_err());
+        let db_ref = document_to_teon_value(&doc!{"$ref": "users"});
+        assert!(MongoDBTransaction::db_ref_filter(&db_ref, "User", "users", "shop").is_err());
+    }
+
+    #[test]
+    fn relation_counts_are_written_per_distinct_count() {
+        let (a, b, c) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let rows = vec![
+            doc!{"_id": a, "postsCount": 2},
+            doc!{"_id": b, "postsCount": 0},
+            doc!{"_id": c, "postsCount": 2},
+        ];
+        assert_eq!(MongoDBTransaction::relation_count_updates("postsCount", rows), vec![
+            (doc!{"_id": {"$in": [a, c]}}, doc!{"$set": {"postsCount": 2}}),
+            (doc!{"_id": {"$in": [b]}}, doc!{"$set": {"postsCount": 0}}),
+        ]);
+    }
+
+    #[test]
+    fn large_relation_count_groups_are_split() {
+        let rows = (0..super::RELATION_COUNT_UPDATE_BATCH + 1).map(|_| doc!{"_id": ObjectId::new(), "postsCount": 1}).collect();
+        let updates = MongoDBTransaction::relation_count_updates("postsCount", rows);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[1].0.get_document("_id").unwrap().get_array("$in").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lookups_over_the_threshold_warn() {
+        let (options, received) = recording_options(None);
+        let include = teon!({"posts": true, "comments": true});
+        assert!(MongoDBTransaction::check_lookup_count(&options, "users", &include, 99, 100).is_ok());
+        assert!(received.lock().unwrap().is_empty());
+        assert!(MongoDBTransaction::check_lookup_count(&options, "users", &include, 5000, 100).is_ok());
+        assert_eq!(*received.lock().unwrap(), vec!["warning: included relations [posts, comments] of users would run per record over 5000 records, consider paginating".to_owned()]);
+    }
+
+    #[test]
+    fn lookups_over_the_threshold_are_rejected_when_configured() {
+        let (mut options, received) = recording_options(None);
+        options.lookup_guard = LookupGuard::Reject;
+        let include = teon!({"posts": true});
+        assert!(MongoDBTransaction::check_lookup_count(&options, "users", &include, 99, 100).is_ok());
+        let message = MongoDBTransaction::check_lookup_count(&options, "users", &include, 100, 100).unwrap_err();
+        assert_eq!(message, "included relations [posts] of users would run per record over 100 records, consider paginating");
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn schema_drift_is_counted_per_field() {
+        let documents = vec![
+            doc!{"_id": 1, "age": 30, "full_name": "Ann"},
+            doc!{"_id": 2, "age": "thirty", "full_name": 7},
+            doc!{"_id": 3, "age": "31"},
+        ];
+        let columns = [("age", "age"), ("fullName", "full_name")];
+        let report = MongoDBTransaction::schema_drift_report(&documents, &columns, |index, bson| match index {
+            0 => bson.as_i32().is_some(),
+            _ => bson.as_str().is_some(),
+        });
+        assert_eq!(report.get("sampled").unwrap().as_int64(), Some(3));
+        let mismatches = report.get("mismatches").unwrap();
+        assert_eq!(mismatches.get("age").unwrap().as_int64(), Some(2));
+        assert_eq!(mismatches.get("fullName").unwrap().as_int64(), Some(1));
+    }
+
+    #[test]
+    fn schema_without_drift_reports_no_mismatches() {
+        let documents = vec![doc!{"age": 30}, doc!{}];
+        let report = MongoDBTransaction::schema_drift_report(&documents, &[("age", "age")], |_, bson| bson.as_i32().is_some());
+        assert_eq!(report.get("sampled").unwrap().as_int64(), Some(2));
+        assert!(report.get("mismatches").unwrap().as_dictionary().unwrap().is_empty());
+    }
+
+    #[test]
+    fn bit_operands_match_the_field_width() {
+        assert_eq!(MongoDBTransaction::bit_operand(&Type::Int, "bitOr", &Value::Int(4), "flags").unwrap(), doc!{"or": 4});
+        assert_eq!(MongoDBTransaction::bit_operand(&Type::Int, "bitAnd", &Value::Int64(6), "flags").unwrap(), doc!{"and": 6});
+        assert_eq!(MongoDBTransaction::bit_operand(&Type::Int64, "bitXor", &Value::Int(1), "flags").unwrap(), doc!{"xor": 1_i64});
+        assert_eq!(MongoDBTransaction::bit_operand(&Type::Optional(Box::new(Type::Int64)), "bitOr", &Value::Int64(1 << 40), "flags").unwrap(), doc!{"or": 1_i64 << 40});
+    }
+
+    #[test]
+    fn non_integer_bit_operands_are_rejected() {
+        assert!(MongoDBTransaction::bit_operand(&Type::Int, "bitOr", &Value::Float(4.0), "flags").is_err());
+        assert!(MongoDBTransaction::bit_operand(&Type::Int, "bitAnd", &Value::String("4".to_owned()), "flags").is_err());
+        assert!(MongoDBTransaction::bit_operand(&Type::Int, "bitXor", &Value::Int64(1 << 40), "flags").is_err());
+        assert!(MongoDBTransaction::bit_operand(&Type::Float, "bitOr", &Value::Int(4), "ratio").is_err());
+    }
+
+    #[test]
+    fn large_results_are_decoded_off_the_task() {
+        assert!(MongoDBTransaction::decodes_off_task(Some(1000), 1000));
+        assert!(!MongoDBTransaction::decodes_off_task(Some(1000), 999));
+        assert!(!MongoDBTransaction::decodes_off_task(None, 1_000_000));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn offloaded_decoding_leaves_the_runtime_free() {
+        let runtime_thread = std::thread::current().id();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        });
+        let decoded = MongoDBTransaction::decode_off_task(move || {
+            // a slow decode, the single runtime thread keeps ticking meanwhile
+            std::thread::sleep(Duration::from_millis(100));
+            let documents: Vec<Document> = (0..10_000).map(|i| doc!{"index": i}).collect();
+            Ok((std::thread::current().id(), documents.iter().map(document_to_teon_value).collect::<Vec<_>>()))
+        }).await.unwrap();
+        ticker.abort();
+        assert_ne!(decoded.0, runtime_thread);
+        assert_eq!(decoded.1.len(), 10_000);
+        assert!(ticks.load(Ordering::SeqCst) > 1);
+    }
+
+    #[tokio::test]
+    async fn failed_offloaded_decoding_is_an_error() {
+        let result = MongoDBTransaction::decode_off_task::<()>(|| panic!("corrupt document")).await;
+        assert!(result.unwrap_err().to_string().contains("decoding task failed"));
+    }
+
+    #[test]
+    fn raw_queries_take_a_collection_and_a_pipeline() {
+        let value = teon!({"collection": "posts", "pipeline": [{"$match": {"published": true}}, {"$limit": 5}]});
+        let (collection, pipeline) = MongoDBTransaction::raw_query_input(&value).unwrap();
+        assert_eq!(collection, "posts");
+        assert_eq!(pipeline.len(), 2);
+        assert_eq!(pipeline[0], doc!{"$match": {"published": true}});
+        assert!(pipeline[1].contains_key("$limit"));
+    }
+
+    #[test]
+    fn malformed_raw_queries_are_rejected() {
+        assert!(MongoDBTransaction::raw_query_input(&teon!({"pipeline": []})).is_err());
+        assert!(MongoDBTransaction::raw_query_input(&teon!({"collection": "posts"})).is_err());
+        assert!(MongoDBTransaction::raw_query_input(&teon!({"collection": "posts", "pipeline": [1]})).is_err());
+    }
+
+    #[test]
+    fn raw_query_results_decode_to_dictionaries() {
+        let values = MongoDBTransaction::documents_to_values(vec![Ok(doc!{"title": "Hello", "views": 3})], path![]).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].get("title").and_then(|t| t.as_str()), Some("Hello"));
+        let failed = vec![Ok(doc!{}), Err(MongoDBError::from(std::io::ErrorKind::ConnectionReset))];
+        assert!(MongoDBTransaction::documents_to_values(failed, path![]).is_err());
+    }
+
+    #[test]
+    fn pages_and_totals_share_a_facet() {
+        let records = vec![doc!{"$match": {"published": true}}, doc!{"$skip": 10_i64}, doc!{"$limit": 10_i64}];
+        let total = vec![doc!{"$match": {"published": true}}, doc!{"$count": "count"}];
+        assert_eq!(MongoDBTransaction::facet_pipeline(records.clone(), total.clone()), vec![
+            doc!{"$facet": {"records": records, "total": total}},
+        ]);
+    }
+
+    #[test]
+    fn text_searches_run_ahead_of_the_facet() {
+        let text = doc!{"$match": {"$text": {"$search": "mongo"}}};
+        let records = vec![text.clone(), doc!{"$limit": 5_i64}];
+        let total = vec![text.clone(), doc!{"$count": "count"}];
+        assert_eq!(MongoDBTransaction::facet_pipeline(records, total), vec![
+            text,
+            doc!{"$facet": {"records": [{"$limit": 5_i64}], "total": [{"$count": "count"}]}},
+        ]);
+        assert_eq!(MongoDBTransaction::facet_pipeline(vec![], vec![doc!{"$count": "count"}]), vec![
+            doc!{"$facet": {"records": [{"$match": {}}], "total": [{"$count": "count"}]}},
+        ]);
+    }
+
+    #[test]
+    fn facet_results_hold_the_page_and_the_total() {
+        let facet = doc!{"records": [{"_id": 1}, {"_id": 2}], "total": [{"count": 42}]};
+        assert_eq!(MongoDBTransaction::facet_result(&facet), (vec![doc!{"_id": 1}, doc!{"_id": 2}], 42));
+        let empty = doc!{"records": [], "total": []};
+        assert_eq!(MongoDBTransaction::facet_result(&empty), (vec![], 0));
+    }
+
+    #[test]
+    fn changing_the_primary_key_is_rejected() {
+        let oid = ObjectId::new();
+        let identifier = doc!{"_id": oid};
+        let error = MongoDBTransaction::primary_key_unchanged(&identifier, "_id", "id", Some(&Bson::ObjectId(ObjectId::new()))).unwrap_err();
+        assert_eq!(error, "cannot modify primary key `id`");
+        assert!(MongoDBTransaction::primary_key_unchanged(&identifier, "_id", "id", None).is_err());
+    }
+
+    #[test]
+    fn unchanged_primary_keys_and_other_columns_are_written() {
+        let identifier = doc!{"tenant_id": 1, "email": "ann@example.com"};
+        assert_eq!(MongoDBTransaction::primary_key_unchanged(&identifier, "email", "email", Some(&Bson::String("ann@example.com".to_owned()))), Ok(true));
+        assert_eq!(MongoDBTransaction::primary_key_unchanged(&identifier, "name", "name", Some(&Bson::String("Ann".to_owned()))), Ok(false));
+        assert_eq!(MongoDBTransaction::primary_key_unchanged(&identifier, "visits", "visits", None), Ok(false));
+    }
+
+    #[test]
+    fn touching_advances_the_timestamp() {
+        let options = ConnectionOptions::default();
+        let saved = Utc::now().trunc_subsecs(3);
+        std::thread::sleep(Duration::from_millis(2));
+        let (set, now) = MongoDBTransaction::touch_update(&options, &Type::DateTime, "updated_at").unwrap();
+        let Value::DateTime(now) = now else {
+            panic!("expected a datetime");
+        };
+        assert!(now > saved);
+        assert_eq!(now, now.trunc_subsecs(3));
+        assert_eq!(set, doc!{"updated_at": bson::DateTime::from(now)});
+    }
+
+    #[test]
+    fn compound_group_keys_yield_every_component() {
+        let id = Bson::Document(doc!{"author_id": 7, "status": "draft", "__unknown": 1});
+        let field_of = |column_name: &str| match column_name {
+            "author_id" => Some("authorId"),
+            "status" => Some("status"),
+            _ => None,
+        };
+        assert_eq!(MongoDBTransaction::group_key_components(&id, field_of), vec![
+            ("authorId", &Bson::Int32(7)),
+            ("status", &Bson::String("draft".to_owned())),
+        ]);
+        assert!(MongoDBTransaction::group_key_components(&Bson::Null, field_of).is_empty());
+    }
+
+    fn transaction(options: ConnectionOptions) -> MongoDBTransaction {
+        // the client connects lazily, nothing here reaches a server
+        let client = Client::with_options(ClientOptions::builder().build()).unwrap();
+        MongoDBTransaction {
+            database: client.database("test"),
+            owned_session: None,
+            committed: Arc::new(AtomicBool::new(false)),
+            options: Arc::new(options),
+            operation: OperationOptions::default(),
+        }
+    }
+
+    fn recording_options(slow_query_threshold: Option<Duration>) -> (ConnectionOptions, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let sink = received.clone();
+        let options = ConnectionOptions {
+            slow_query_threshold,
+            warning_handler: WarningHandler::new(move |message| sink.lock().unwrap().push(message.to_owned())),
+            ..Default::default()
+        };
+        (options, received)
+    }
+
+    #[tokio::test]
+    async fn queries_over_the_threshold_are_reported() {
+        let (options, received) = recording_options(Some(Duration::ZERO));
+        let pipeline = vec![doc!{"$match": {"published": true}}];
+        transaction(options).report_slow_query("aggregate", "posts", Instant::now(), Some(&pipeline));
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert!(received[0].starts_with("warning: slow MongoDB aggregate on posts took"));
+        assert!(received[0].contains("published"));
+    }
+
+    #[tokio::test]
+    async fn queries_under_the_threshold_are_not_reported() {
+        let (options, received) = recording_options(Some(Duration::from_secs(60)));
+        transaction(options).report_slow_query("update", "posts", Instant::now(), None);
+        let (options, unset) = recording_options(None);
+        transaction(options).report_slow_query("update", "posts", Instant::now(), None);
+        assert!(received.lock().unwrap().is_empty());
+        assert!(unset.lock().unwrap().is_empty());
+    }
+}
@@ -1,21 +1,104 @@
+use bson::Bson;
 use mongodb::IndexModel;
 use teo_runtime::sort::Sort;
 use teo_runtime::model::{Index, index::Item};
 use teo_runtime::model::index::Type;
+use crate::connector::UniqueNulls;
 
 pub trait FromIndexModel {
     fn from_index_model(index_model: &IndexModel) -> Self;
 }
 
 impl FromIndexModel for Index {
+    /// Only the user meaningful parts, the name, uniqueness and keys, are read.
+    /// Server added defaults like `v`, `ns` and `background` are ignored so
+    /// they never make an index differ from its model definition.
     fn from_index_model(index_model: &IndexModel) -> Self {
-        let unique_result = index_model.options.as_ref().unwrap().unique;
-        let unique = unique_result.unwrap_or(false);
+        let options = index_model.options.as_ref();
+        let unique = options.and_then(|o| o.unique).unwrap_or(false);
+        let name = options.and_then(|o| o.name.clone()).unwrap_or_default();
         let mut items: Vec<Item> = Vec::new();
         for (k, v) in &index_model.keys {
-            let item = Item::new(k.clone(), if v.as_i32().unwrap() == 1 { Sort::Asc } else { Sort::Desc }, None);
+            let item = Item::new(k.clone(), if key_direction(v) >= 0.0 { Sort::Asc } else { Sort::Desc }, None);
             items.push(item);
         }
-        Index::new(if unique { Type::Unique } else { Type::Index }, index_model.options.as_ref().unwrap().name.as_ref().unwrap().to_string(), items)
+        Index::new(if unique { Type::Unique } else { Type::Index }, name, items)
+    }
+}
+
+impl FromIndexModel for UniqueNulls {
+    /// Read from the options `index_model` is built with by migration.
+    fn from_index_model(index_model: &IndexModel) -> Self {
+        let options = index_model.options.as_ref();
+        if options.and_then(|o| o.partial_filter_expression.as_ref()).is_some() {
+            UniqueNulls::PartialExists
+        } else if options.and_then(|o| o.sparse).unwrap_or(false) {
+            UniqueNulls::Sparse
+        } else {
+            UniqueNulls::Strict
+        }
+    }
+}
+
+/// Servers may report key directions as any numeric type.
+fn key_direction(value: &Bson) -> f64 {
+    match value {
+        Bson::Int32(i) => *i as f64,
+        Bson::Int64(i) => *i as f64,
+        Bson::Double(f) => *f,
+        _ => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{doc, Bson};
+    use mongodb::IndexModel;
+    use teo_runtime::sort::Sort;
+    use teo_runtime::model::{Index, index::Item};
+    use teo_runtime::model::index::Type;
+    use crate::connector::UniqueNulls;
+    use super::FromIndexModel;
+
+    /// An index as `listIndexes` reports it, with server added defaults.
+    fn listed_index(keys: bson::Document, unique: bool) -> IndexModel {
+        bson::from_document(doc!{
+            "v": 2,
+            "key": keys,
+            "name": "a_b",
+            "ns": "db.records",
+            "background": true,
+            "unique": unique,
+        }).unwrap()
+    }
+
+    #[test]
+    fn server_defaults_are_ignored() {
+        let index = Index::from_index_model(&listed_index(doc!{"a": 1, "b": -1}, true));
+        let expected = Index::new(Type::Unique, "a_b".to_owned(), vec![
+            Item::new("a".to_owned(), Sort::Asc, None),
+            Item::new("b".to_owned(), Sort::Desc, None),
+        ]);
+        assert!(index == expected);
+    }
+
+    #[test]
+    fn key_directions_of_any_numeric_type_are_read() {
+        let index = Index::from_index_model(&listed_index(doc!{"a": 1.0, "b": Bson::Int64(-1)}, false));
+        let expected = Index::new(Type::Index, "a_b".to_owned(), vec![
+            Item::new("a".to_owned(), Sort::Asc, None),
+            Item::new("b".to_owned(), Sort::Desc, None),
+        ]);
+        assert!(index == expected);
+    }
+
+    #[test]
+    fn unique_nulls_are_read_from_options() {
+        let sparse: IndexModel = bson::from_document(doc!{"key": {"a": 1}, "name": "a", "unique": true, "sparse": true}).unwrap();
+        let partial: IndexModel = bson::from_document(doc!{"key": {"a": 1}, "name": "a", "unique": true, "partialFilterExpression": {"a": {"$exists": true}}}).unwrap();
+        let strict: IndexModel = bson::from_document(doc!{"key": {"a": 1}, "name": "a", "unique": true}).unwrap();
+        assert_eq!(UniqueNulls::from_index_model(&sparse), UniqueNulls::Sparse);
+        assert_eq!(UniqueNulls::from_index_model(&partial), UniqueNulls::PartialExists);
+        assert_eq!(UniqueNulls::from_index_model(&strict), UniqueNulls::Strict);
     }
 }